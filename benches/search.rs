@@ -0,0 +1,15 @@
+//! `cargo bench` prints the default measurements of `md4rip::bench`; set
+//! MD4RIP_BENCH_SEED to change the seed
+
+use md4rip::{bench, BenchConfig};
+
+fn main() {
+    let mut config = BenchConfig::default();
+    if let Some(seed) = std::env::var("MD4RIP_BENCH_SEED")
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        config.seed = seed;
+    }
+    print!("{}", bench(&config).to_text());
+}
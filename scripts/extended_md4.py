@@ -0,0 +1,71 @@
+#!/usr/bin/env python3
+"""Extended MD4 (RFC 1186, section "Extensions") written from the RFC alone.
+
+Prints the 256-bit digests checked by `state_tests::extended_digests`:
+
+    python3 scripts/extended_md4.py
+"""
+
+import struct
+
+MASK = 0xFFFFFFFF
+
+
+def rotl(x, s):
+    return ((x << s) | (x >> (32 - s))) & MASK
+
+
+def f(x, y, z):
+    return (x & y) | (~x & z)
+
+
+def g(x, y, z):
+    return (x & y) | (x & z) | (y & z)
+
+
+def h(x, y, z):
+    return x ^ y ^ z
+
+
+# Word order and shifts of the three rounds
+ROUNDS = [
+    (f, range(16), (3, 7, 11, 19)),
+    (g, [0, 4, 8, 12, 1, 5, 9, 13, 2, 6, 10, 14, 3, 7, 11, 15], (3, 5, 9, 13)),
+    (h, [0, 8, 4, 12, 2, 10, 6, 14, 1, 9, 5, 13, 3, 11, 7, 15], (3, 9, 11, 15)),
+]
+
+# Round constants of the first line (sqrt 2, sqrt 3) and the second (cube
+# roots of 2 and 3); round 1 adds nothing in either
+LEFT = (0, 0x5A827999, 0x6ED9EBA1)
+RIGHT = (0, 0x50A28BE6, 0x5C4DD124)
+
+
+def line(state, x, constants):
+    a, b, c, d = state
+    for (func, order, shifts), k in zip(ROUNDS, constants):
+        for i, j in enumerate(order):
+            t = rotl((a + func(b, c, d) + x[j] + k) & MASK, shifts[i % 4])
+            a, b, c, d = d, t, b, c
+    return [(v + w) & MASK for v, w in zip(state, (a, b, c, d))]
+
+
+def extended_md4(message):
+    length = len(message) * 8
+    message += b"\x80"
+    message += b"\x00" * ((56 - len(message)) % 64)
+    message += struct.pack("<Q", length)
+
+    left = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476]
+    right = [0x33221100, 0x77665544, 0xBBAA9988, 0xFFEEDDCC]
+    for offset in range(0, len(message), 64):
+        x = struct.unpack("<16I", message[offset : offset + 64])
+        left = line(left, x, LEFT)
+        right = line(right, x, RIGHT)
+        # The A registers of both lines are exchanged after every block
+        left[0], right[0] = right[0], left[0]
+    return struct.pack("<8I", *left, *right).hex()
+
+
+if __name__ == "__main__":
+    for m in [b"", b"abc", b"message digest", b"abcdefghijklmnopqrstuvwxyz"]:
+        print('%-30r %s' % (m, extended_md4(m)))
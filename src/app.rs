@@ -1,31 +1,502 @@
-use crate::builder::Builder;
+use crate::append::append_common_suffix;
+use crate::batch::batch;
+use crate::bench::{bench, BenchConfig};
+use crate::builder::{Builder, DobbertinBuilder, MD5Builder, Messages};
+use crate::cache::CollisionCache;
+use crate::collision::CollisionFinder;
+use crate::common::U8Block;
+use crate::compression::CollisionSearch;
+use crate::conforming::conforming_message;
+use crate::dobbertin::compare_algorithms;
+use crate::forge::{extend, forge, forge_range};
+use crate::multi::{combine, multi_collide};
+use crate::offset::Offset;
+use crate::path::DifferentialPath;
+use crate::reduced::lab;
+use crate::rsync::{rsync_block_collision, rsync_checksum};
+use crate::search::PathCompletion;
+use crate::state::{ExtendedMD4State, ExtendedMd4, MD4State};
+use crate::trace::{trace, trace_extended};
+use crate::verify::verify;
 use itertools::Itertools;
 use md4::{Digest, Md4};
+use md5::Md5;
+use serde::Serialize;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use structopt::clap::{Error, ErrorKind};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+#[structopt(raw(setting = "structopt::clap::AppSettings::ArgsNegateSubcommands"))]
+#[structopt(raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen"))]
 #[structopt(name = "md4rip", about = "A MD4 Collision Generator.")]
 pub struct Opt {
-    /// The input file to use
-    #[structopt(name = "INPUT", parse(try_from_os_str = "parse_existing_file"))]
-    input: PathBuf,
-    /// The collision's starting position
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+    /// The input file to use, or - for stdin
+    #[structopt(name = "INPUT", parse(try_from_os_str = "parse_input_file"))]
+    input: Option<PathBuf>,
+    /// The collision's starting position: a byte count (decimal or 0x hex),
+    /// -N or end-N bytes before the end, end, or after:HEX and after-text:TEXT
+    /// to start just after the first occurrence of a byte string
     #[structopt(name = "OFFSET")]
-    offset: u64,
+    offset: Option<Offset>,
     #[structopt(name = "OUTPUT1", parse(from_os_str))]
-    /// Path for output file 1
-    output1: PathBuf,
+    /// Path for output file 1, or - for stdout
+    output1: Option<PathBuf>,
     #[structopt(name = "OUTPUT2", parse(from_os_str))]
-    /// Path for output file 2
-    output2: PathBuf,
+    /// Path for output file 2, or - for stdout
+    output2: Option<PathBuf>,
+    /// Only write the padding and colliding message to each output, not the whole file
+    #[structopt(short = "b", long = "blocks")]
+    blocks: bool,
     /// Enable to find specific pattern (0x__01FEFF) for JPEG COMMENT on second word
     #[structopt(short = "j", long = "jpeg")]
     jpeg_mode: bool,
+    /// Load the differential path from a file (text, or JSON with a .json extension)
+    #[structopt(
+        short = "p",
+        long = "path",
+        parse(try_from_os_str = "parse_existing_file")
+    )]
+    path: Option<PathBuf>,
+    /// The hash function to collide
+    #[structopt(
+        long = "hash",
+        default_value = "md4",
+        raw(possible_values = "&[\"md4\", \"md5\"]")
+    )]
+    hash: String,
+    /// The MD4 collision attack to run
+    #[structopt(
+        short = "a",
+        long = "algorithm",
+        default_value = "wang",
+        raw(possible_values = "&[\"wang\", \"dobbertin\"]")
+    )]
+    algorithm: String,
+    /// Output format; json and toml print a single document and send the
+    /// rest of the output to stderr
+    #[structopt(
+        short = "f",
+        long = "format",
+        default_value = "text",
+        raw(possible_values = "&[\"text\", \"json\", \"toml\"]")
+    )]
+    format: String,
+    /// Start from this chaining state as four hex words instead of the IV
+    #[structopt(
+        long = "iv",
+        parse(try_from_str = "parse_word"),
+        raw(number_of_values = "4")
+    )]
+    iv: Vec<u32>,
+    /// Always search, instead of reusing a collision cached for the same
+    /// chaining state and options
+    #[structopt(long = "no-cache")]
+    no_cache: bool,
+}
+
+// Print a line of human-readable output, on stderr when stdout carries a
+// structured document or output data
+macro_rules! note {
+    ($structured:expr, $($arg:tt)*) => {
+        if $structured {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Result of the collision search, for `--format json` and `--format toml`
+#[derive(Debug, Serialize)]
+struct CollisionReport {
+    hash: String,
+    offset: u64,
+    padding: String,
+    message1: String,
+    message2: String,
+    /// Chaining state before the colliding block, as hex words
+    state: [String; 4],
+    output1: String,
+    output2: String,
+    digest1: String,
+    digest2: String,
+    matched: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Create two blocks with identical rsync (legacy MD4) strong checksums
+    #[structopt(name = "rsync")]
+    Rsync {
+        /// The rsync block size in bytes
+        #[structopt(name = "BLOCK_SIZE")]
+        block_size: usize,
+        /// The checksum seed used by the rsync session
+        #[structopt(name = "SEED")]
+        seed: u32,
+        #[structopt(name = "OUTPUT1", parse(from_os_str))]
+        /// Path for output block 1
+        output1: PathBuf,
+        #[structopt(name = "OUTPUT2", parse(from_os_str))]
+        /// Path for output block 2
+        output2: PathBuf,
+    },
+    /// Forge the digest of a secret-prefixed message extended with new data
+    #[structopt(name = "forge")]
+    Forge {
+        /// The known MD4 digest of secret || message, in hex
+        #[structopt(name = "DIGEST", parse(try_from_str = "parse_digest"))]
+        digest: [u8; 16],
+        /// Length of secret || message, or of message alone with --secret-length
+        #[structopt(name = "LENGTH")]
+        length: u64,
+        /// The data to append
+        #[structopt(name = "EXTENSION")]
+        extension: String,
+        /// Treat EXTENSION as hex-encoded bytes
+        #[structopt(short = "x", long = "hex")]
+        hex: bool,
+        /// Try every candidate secret length in a range (e.g. 8..32)
+        #[structopt(
+            short = "s",
+            long = "secret-length",
+            parse(try_from_str = "parse_range")
+        )]
+        secret_length: Option<RangeInclusive<u64>>,
+    },
+    /// Append common data to an existing collision pair
+    #[structopt(name = "append")]
+    Append {
+        /// Truncate both inputs at this offset before appending (defaults to their end)
+        #[structopt(short = "o", long = "offset")]
+        offset: Option<u64>,
+        /// The first file of the collision pair
+        #[structopt(name = "INPUT1", parse(try_from_os_str = "parse_existing_file"))]
+        input1: PathBuf,
+        /// The second file of the collision pair
+        #[structopt(name = "INPUT2", parse(try_from_os_str = "parse_existing_file"))]
+        input2: PathBuf,
+        /// The file whose content is appended to both inputs
+        #[structopt(name = "SUFFIX", parse(try_from_os_str = "parse_existing_file"))]
+        suffix: PathBuf,
+        #[structopt(name = "OUTPUT1", parse(from_os_str))]
+        /// Path for output file 1
+        output1: PathBuf,
+        #[structopt(name = "OUTPUT2", parse(from_os_str))]
+        /// Path for output file 2
+        output2: PathBuf,
+    },
+    /// Analyse two files claimed to be an MD4 collision
+    #[structopt(name = "verify")]
+    Verify {
+        /// The first file
+        #[structopt(name = "A", parse(try_from_os_str = "parse_existing_file"))]
+        a: PathBuf,
+        /// The second file
+        #[structopt(name = "B", parse(try_from_os_str = "parse_existing_file"))]
+        b: PathBuf,
+    },
+    /// Trace a block and its Wang partner step by step against the sufficient conditions
+    #[structopt(name = "trace")]
+    Trace {
+        /// The initial chaining state as four hex words (defaults to the MD4 IV)
+        #[structopt(
+            short = "s",
+            long = "state",
+            parse(try_from_str = "parse_word"),
+            raw(number_of_values = "4")
+        )]
+        state: Vec<u32>,
+        /// Trace both lines of Extended MD4 instead of MD4
+        #[structopt(short = "e", long = "extended")]
+        extended: bool,
+        /// The initial state of the second Extended MD4 line as four hex words
+        #[structopt(
+            long = "right-state",
+            parse(try_from_str = "parse_word"),
+            raw(number_of_values = "4")
+        )]
+        right_state: Vec<u32>,
+        /// Output format
+        #[structopt(
+            short = "f",
+            long = "format",
+            default_value = "table",
+            raw(possible_values = "&[\"table\", \"json\"]")
+        )]
+        format: String,
+        /// Load the differential path from a file (text, or JSON with a .json extension)
+        #[structopt(
+            short = "p",
+            long = "path",
+            parse(try_from_os_str = "parse_existing_file")
+        )]
+        path: Option<PathBuf>,
+        /// The first message block as 64 hex-encoded bytes
+        #[structopt(name = "BLOCK", parse(try_from_str = "parse_block"))]
+        block: U8Block,
+    },
+    /// Complete a known differential path, keeping its message differences and first steps
+    #[structopt(name = "complete")]
+    Complete {
+        /// Target chaining-value difference as four hex words (defaults to zero)
+        #[structopt(
+            short = "t",
+            long = "target",
+            parse(try_from_str = "parse_word"),
+            raw(number_of_values = "4")
+        )]
+        target: Vec<u32>,
+        /// Load the path to complete from a file (text, or JSON with a .json extension)
+        #[structopt(
+            short = "p",
+            long = "path",
+            parse(try_from_os_str = "parse_existing_file")
+        )]
+        path: Option<PathBuf>,
+        /// Number of steps of the path kept as they are; round 1 is derived if it is not kept
+        #[structopt(short = "k", long = "keep", default_value = "18")]
+        keep: usize,
+        /// Number of partial paths kept at each step
+        #[structopt(short = "w", long = "width", default_value = "16")]
+        width: usize,
+        /// Number of children tried for each partial path
+        #[structopt(short = "e", long = "expansions", default_value = "4")]
+        expansions: usize,
+        /// Seed for the random choices of the search
+        #[structopt(long = "seed", default_value = "0")]
+        seed: u64,
+        /// Output format
+        #[structopt(
+            short = "f",
+            long = "format",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"json\"]")
+        )]
+        format: String,
+    },
+    /// Generate messages conforming to a differential path along with their
+    /// second preimages (not the weak messages of Yu et al., see README)
+    #[structopt(name = "conforming")]
+    Conforming {
+        /// The initial chaining state as four hex words (defaults to the MD4 IV)
+        #[structopt(
+            short = "s",
+            long = "state",
+            parse(try_from_str = "parse_word"),
+            raw(number_of_values = "4")
+        )]
+        state: Vec<u32>,
+        /// Load the differential path from a file (text, or JSON with a .json extension)
+        #[structopt(
+            short = "p",
+            long = "path",
+            parse(try_from_os_str = "parse_existing_file")
+        )]
+        path: Option<PathBuf>,
+        /// Fix bytes of the conforming message as OFFSET:HEX (each byte costs a factor of ~256)
+        #[structopt(short = "F", long = "fix", parse(try_from_str = "parse_fixed"))]
+        fixed: Vec<Vec<(usize, u8)>>,
+        /// Number of conforming messages to generate
+        #[structopt(short = "n", long = "count", default_value = "1")]
+        count: usize,
+    },
+    /// Break MD4 reduced to fewer steps and report how cheap each variant is
+    #[structopt(name = "lab")]
+    Lab {
+        /// Numbers of steps of the variants to break (32 omits round 3)
+        #[structopt(
+            short = "s",
+            long = "steps",
+            default_value = "8,16,24,32,40,48",
+            use_delimiter = true
+        )]
+        steps: Vec<usize>,
+        /// Give up on a collision after this many attempts
+        #[structopt(short = "n", long = "attempts", default_value = "16777216")]
+        attempts: u64,
+        /// Seed of the collision searches and of the preimage targets
+        #[structopt(long = "seed", default_value = "0")]
+        seed: u64,
+    },
+    /// Find two blocks colliding from a given MD4 chaining state
+    #[structopt(name = "state")]
+    State {
+        /// The chaining state as four hex words
+        #[structopt(
+            name = "STATE",
+            parse(try_from_str = "parse_word"),
+            raw(number_of_values = "4")
+        )]
+        state: Vec<u32>,
+        /// Load the differential path from a file (text, or JSON with a .json extension)
+        #[structopt(
+            short = "p",
+            long = "path",
+            parse(try_from_os_str = "parse_existing_file")
+        )]
+        path: Option<PathBuf>,
+        /// Write the blocks to these two files instead of only printing them
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            raw(number_of_values = "2")
+        )]
+        outputs: Vec<PathBuf>,
+    },
+    /// Create independent MD4 collisions at several offsets of one file
+    #[structopt(name = "multi")]
+    #[structopt(raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen"))]
+    Multi {
+        /// The input file to use, or - for stdin
+        #[structopt(name = "INPUT", parse(try_from_os_str = "parse_input_file"))]
+        input: PathBuf,
+        /// The increasing offsets of the collisions, in any form OFFSET accepts
+        #[structopt(name = "OFFSET", required = true)]
+        offsets: Vec<Offset>,
+        /// Outputs are written to PREFIX.BITS, where bit i picks the message of collision i
+        #[structopt(short = "o", long = "out", parse(from_os_str))]
+        prefix: PathBuf,
+        /// Only write these combinations, given as bit strings such as 0110
+        #[structopt(short = "s", long = "select", use_delimiter = true)]
+        select: Vec<String>,
+    },
+    /// Generate many independent MD4 collisions, written as JSON lines
+    #[structopt(name = "batch")]
+    Batch {
+        /// Number of collisions to generate
+        #[structopt(short = "c", long = "count")]
+        count: u64,
+        /// The prefix every collision follows (defaults to none)
+        #[structopt(
+            short = "p",
+            long = "prefix-file",
+            parse(try_from_os_str = "parse_existing_file")
+        )]
+        prefix_file: Option<PathBuf>,
+        /// Path for the results, one JSON object per collision
+        #[structopt(short = "o", long = "out", parse(from_os_str))]
+        out: PathBuf,
+        /// Seed of the first search, the next ones counting up (defaults to random)
+        #[structopt(long = "seed")]
+        seed: Option<u64>,
+        /// Number of searches run in parallel
+        #[structopt(short = "j", long = "jobs", default_value = "1")]
+        jobs: usize,
+    },
+    /// Time the Wang and Dobbertin MD4 collision attacks against each other
+    #[structopt(name = "compare")]
+    Compare {
+        /// Number of collisions each attack has to find
+        #[structopt(short = "n", long = "collisions", default_value = "4")]
+        collisions: usize,
+        /// Seed of both searches
+        #[structopt(long = "seed", default_value = "0")]
+        seed: u64,
+    },
+    /// Measure the speed of the MD4 collision search and compression function
+    #[structopt(name = "bench")]
+    Bench {
+        /// Seed of the search and of the hashed blocks
+        #[structopt(long = "seed", default_value = "1")]
+        seed: u64,
+        /// Number of collisions to find
+        #[structopt(short = "n", long = "collisions", default_value = "8")]
+        collisions: u64,
+        /// Neighbours tried through the tunnels after each failed message
+        /// (defaults to DEFAULT_TUNNEL_DEPTH, 0 disables the tunnels)
+        #[structopt(long = "tunnel-depth")]
+        tunnel_depth: Option<usize>,
+        /// Output format, both with one measurement per line or entry
+        #[structopt(
+            short = "f",
+            long = "format",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"json\"]")
+        )]
+        format: String,
+    },
+    /// Inspect the cache of MD4 collisions found earlier
+    #[structopt(name = "cache")]
+    Cache {
+        #[structopt(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CacheAction {
+    /// Print the key and messages of every cached collision
+    #[structopt(name = "list")]
+    List,
+    /// Remove every cached collision
+    #[structopt(name = "clear")]
+    Clear,
+}
+
+fn parse_word(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|e| e.to_string())
+}
+
+fn parse_fixed(s: &str) -> Result<Vec<(usize, u8)>, String> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(offset), Some(bytes)) => {
+            let offset = offset.parse::<usize>().map_err(|e| e.to_string())?;
+            let bytes = hex::decode(bytes).map_err(|e| e.to_string())?;
+            Ok(bytes
+                .into_iter()
+                .enumerate()
+                .map(|(i, byte)| (offset + i, byte))
+                .collect())
+        }
+        _ => Err(String::from("expected OFFSET:HEX")),
+    }
+}
+
+fn parse_block(s: &str) -> Result<U8Block, String> {
+    let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+    if bytes.len() != 64 {
+        return Err(String::from("block must be 64 bytes"));
+    }
+    Ok(U8Block::clone_from_slice(&bytes))
+}
+
+fn parse_digest(s: &str) -> Result<[u8; 16], String> {
+    let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+    if bytes.len() != 16 {
+        return Err(String::from("digest must be 16 bytes"));
+    }
+
+    let mut digest = [0u8; 16];
+    digest.copy_from_slice(&bytes);
+    Ok(digest)
+}
+
+fn parse_range(s: &str) -> Result<RangeInclusive<u64>, String> {
+    let parse = |n: &str| n.parse::<u64>().map_err(|e| e.to_string());
+    if let Some(i) = s.find("..=") {
+        Ok(parse(&s[..i])?..=parse(&s[i + 3..])?)
+    } else if let Some(i) = s.find("..") {
+        let end = parse(&s[i + 2..])?;
+        if end == 0 {
+            return Err(String::from("range is empty"));
+        }
+        Ok(parse(&s[..i])?..=end - 1)
+    } else {
+        let n = parse(s)?;
+        Ok(n..=n)
+    }
 }
 
 fn parse_existing_file(s: &OsStr) -> Result<PathBuf, OsString> {
@@ -37,40 +508,66 @@ fn parse_existing_file(s: &OsStr) -> Result<PathBuf, OsString> {
     }
 }
 
+fn parse_input_file(s: &OsStr) -> Result<PathBuf, OsString> {
+    if s == "-" {
+        Ok(PathBuf::from(s))
+    } else {
+        parse_existing_file(s)
+    }
+}
+
 pub struct App {}
 
 impl App {
-    fn write_output(output: &PathBuf, input: &PathBuf, offset: u64, padding: &[u8], m: &[u8]) {
-        let mut input_file = File::open(input)
-            .unwrap_or_else(|_| panic!("failed to open input file {}", input.display()));
-
-        let mut output_file = File::create(output)
-            .unwrap_or_else(|_| panic!("failed to create output file {}", output.display()));
-
-        // Copy input file to output file
-        std::io::copy(&mut input_file, &mut output_file)
-            .unwrap_or_else(|_| panic!("failed to copy input to output file {}", output.display()));
-
-        // Seek output file to offset
-        output_file
-            .seek(SeekFrom::Start(offset))
-            .unwrap_or_else(|_| panic!("seek to offset failed: file {}", output.display()));
-
-        // Write padding
-        output_file.write_all(padding).unwrap_or_else(|_| {
-            panic!(
-                "failed to write padding to output file {}",
-                output.display()
-            )
-        });
+    // Write the input with padding and m overwriting it from offset on, or
+    // only padding and m when `blocks_only` is set
+    fn write_output(
+        output: &mut dyn Write,
+        input: &mut (impl Read + Seek),
+        offset: u64,
+        padding: &[u8],
+        m: &[u8],
+        blocks_only: bool,
+    ) -> std::io::Result<()> {
+        if !blocks_only {
+            input.seek(SeekFrom::Start(0))?;
+            std::io::copy(&mut input.take(offset), output)?;
+        }
 
-        // Write message
-        output_file.write_all(m).unwrap_or_else(|_| {
-            panic!(
-                "failed to write message to output file {}",
-                output.display()
-            )
-        });
+        output.write_all(padding)?;
+        output.write_all(m)?;
+
+        if !blocks_only {
+            let end = offset + (padding.len() + m.len()) as u64;
+            input.seek(SeekFrom::Start(end))?;
+            std::io::copy(input, output)?;
+        }
+        output.flush()
+    }
+
+    // Digest of the data `write` produces, with the hash function named by
+    // --hash, from the given MD4 IV if any
+    fn digest(hash: &str, iv: &[u32], write: impl FnOnce(&mut dyn Write)) -> String {
+        match hash {
+            "md5" => {
+                let mut hasher = Md5::new();
+                write(&mut hasher);
+                hex::encode(hasher.result())
+            }
+            _ if iv.len() == 4 => {
+                let mut data = Vec::new();
+                write(&mut data);
+                let init = MD4State {
+                    s: [iv[0], iv[1], iv[2], iv[3]],
+                };
+                hex::encode(extend(init, 0, &data))
+            }
+            _ => {
+                let mut hasher = Md4::new();
+                write(&mut hasher);
+                hex::encode(hasher.result())
+            }
+        }
     }
 
     pub fn md4sum(path: &PathBuf) -> String {
@@ -84,64 +581,936 @@ impl App {
         format!("{:02x}", hasher.result().iter().format(""))
     }
 
+    pub fn extended_md4sum(path: &PathBuf) -> String {
+        let file =
+            File::open(path).unwrap_or_else(|_| panic!("failed to open file {}", path.display()));
+        let mut reader = BufReader::new(file);
+
+        let mut hasher = ExtendedMd4::default();
+        std::io::copy(&mut reader, &mut hasher).unwrap();
+
+        format!("{:02x}", hasher.result().iter().format(""))
+    }
+
+    pub fn md5sum(path: &PathBuf) -> String {
+        let file =
+            File::open(path).unwrap_or_else(|_| panic!("failed to open file {}", path.display()));
+        let mut reader = BufReader::new(file);
+
+        let mut hasher = Md5::new();
+        std::io::copy(&mut reader, &mut hasher).unwrap();
+
+        format!("{:02x}", hasher.result().iter().format(""))
+    }
+
     pub fn run(opt: Opt) {
-        // Take input file
-        let file = File::open(&opt.input).expect("failed to open input file");
-        let len = file
-            .metadata()
-            .expect("failed to get input file metadata")
-            .len();
-        let limit = opt.offset;
-        if limit > len {
-            println!("======= ERROR: offset is larger than file size");
+        match &opt.cmd {
+            Some(Command::Rsync {
+                block_size,
+                seed,
+                output1,
+                output2,
+            }) => App::rsync(*block_size, *seed, output1, output2),
+            Some(Command::Forge {
+                digest,
+                length,
+                extension,
+                hex,
+                secret_length,
+            }) => App::forge(digest, *length, extension, *hex, secret_length),
+            Some(Command::Append {
+                offset,
+                input1,
+                input2,
+                suffix,
+                output1,
+                output2,
+            }) => App::append(*offset, [input1, input2], suffix, [output1, output2]),
+            Some(Command::Verify { a, b }) => App::verify(a, b),
+            Some(Command::Trace {
+                state,
+                extended,
+                right_state,
+                format,
+                path,
+                block,
+            }) => {
+                if *extended {
+                    App::trace_extended([state, right_state], format, path, block)
+                } else if !right_state.is_empty() {
+                    println!("======= ERROR: --right-state needs --extended");
+                } else {
+                    App::trace(state, format, path, block)
+                }
+            }
+            Some(Command::Complete {
+                target,
+                path,
+                keep,
+                width,
+                expansions,
+                seed,
+                format,
+            }) => App::complete(
+                target,
+                path,
+                *keep,
+                (*width, *expansions),
+                *seed,
+                format,
+            ),
+            Some(Command::Conforming {
+                state,
+                path,
+                fixed,
+                count,
+            }) => App::conforming(state, path, fixed, *count),
+            Some(Command::Lab {
+                steps,
+                attempts,
+                seed,
+            }) => App::lab(steps, *attempts, *seed),
+            Some(Command::Compare { collisions, seed }) => App::compare(*collisions, *seed),
+            Some(Command::State {
+                state,
+                path,
+                outputs,
+            }) => App::state(state, path, outputs),
+            Some(Command::Multi {
+                input,
+                offsets,
+                prefix,
+                select,
+            }) => App::multi(input, offsets, prefix, select),
+            Some(Command::Batch {
+                count,
+                prefix_file,
+                out,
+                seed,
+                jobs,
+            }) => App::batch(*count, prefix_file, out, *seed, *jobs),
+            Some(Command::Bench {
+                seed,
+                collisions,
+                tunnel_depth,
+                format,
+            }) => App::bench(*seed, *collisions, *tunnel_depth, format),
+            Some(Command::Cache { action }) => App::cache(action),
+            None => App::collide(&opt),
+        }
+    }
+
+    fn collide(opt: &Opt) {
+        let (input, offset, output1, output2) =
+            match (&opt.input, &opt.offset, &opt.output1, &opt.output2) {
+                (Some(input), Some(offset), Some(output1), Some(output2)) => {
+                    (input, offset, output1, output2)
+                }
+                _ => Error::with_description(
+                    "INPUT, OFFSET, OUTPUT1 and OUTPUT2 are required without a subcommand",
+                    ErrorKind::MissingRequiredArgument,
+                )
+                .exit(),
+            };
+
+        // Keep stdout for the structured document or the output data
+        let to_stdout = output1 == Path::new("-") || output2 == Path::new("-");
+        let structured = opt.format != "text" || to_stdout;
+        if opt.format != "text" && to_stdout {
+            note!(
+                structured,
+                "======= ERROR: --format {} cannot share stdout with an output",
+                opt.format
+            );
             return;
         }
-        let mut reader = BufReader::new(file).take(limit);
 
-        // Feed prefix into builder
-        let mut builder = Builder::new();
-        builder.set_jpeg_mode(opt.jpeg_mode);
-        std::io::copy(&mut reader, &mut builder).unwrap();
+        // Take input file, or all of stdin
+        if input == Path::new("-") {
+            let mut data = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut data)
+                .expect("failed to read stdin");
+            let len = data.len() as u64;
+            App::collide_from(
+                opt,
+                structured,
+                Cursor::new(data),
+                len,
+                offset,
+                [output1, output2],
+            );
+        } else {
+            let file = File::open(input).expect("failed to open input file");
+            let len = file
+                .metadata()
+                .expect("failed to get input file metadata")
+                .len();
+            App::collide_from(opt, structured, file, len, offset, [output1, output2]);
+        }
+    }
+
+    // Create the collision in `source`; `structured` tells whether stdout is
+    // reserved for a document or output data
+    fn collide_from<R: Read + Seek>(
+        opt: &Opt,
+        structured: bool,
+        mut source: R,
+        len: u64,
+        offset: &Offset,
+        [output1, output2]: [&PathBuf; 2],
+    ) {
+        let offset = match offset.resolve(&mut source, len) {
+            Ok(offset) => offset,
+            Err(e) => {
+                note!(structured, "======= ERROR: {}", e);
+                return;
+            }
+        };
+        let limit = offset;
+        source
+            .seek(SeekFrom::Start(0))
+            .expect("failed to seek input");
+        let mut reader = BufReader::new(&mut source).take(limit);
+
+        // Feed prefix into builder, and build
+        let name = match opt.hash.as_str() {
+            "md4" if opt.algorithm == "wang" => "MD4Sum",
+            "md4" if opt.jpeg_mode || opt.path.is_some() => {
+                note!(
+                    structured,
+                    "======= ERROR: --jpeg and --path are only supported by the Wang attack"
+                );
+                return;
+            }
+            "md4" => "MD4Sum",
+            _ if !opt.iv.is_empty() => {
+                note!(structured, "======= ERROR: --iv is only supported for MD4");
+                return;
+            }
+            _ if opt.algorithm != "wang" => {
+                note!(
+                    structured,
+                    "======= ERROR: --algorithm is only supported for MD4"
+                );
+                return;
+            }
+            _ if opt.jpeg_mode || opt.path.is_some() => {
+                note!(
+                    structured,
+                    "======= ERROR: --jpeg and --path are only supported for MD4"
+                );
+                return;
+            }
+            _ => "MD5Sum",
+        };
+        let iv = if opt.iv.is_empty() {
+            None
+        } else {
+            Some([opt.iv[0], opt.iv[1], opt.iv[2], opt.iv[3]])
+        };
+        let cache = if opt.no_cache {
+            None
+        } else {
+            CollisionCache::open_default()
+        };
+        let start = Instant::now();
+        let result = match opt.hash.as_str() {
+            "md5" => App::build(MD5Builder::default(), &mut reader).map(|(m, s)| (m, s.s)),
+            _ if opt.algorithm == "dobbertin" => {
+                let mut builder = DobbertinBuilder::default();
+                if let Some(s) = iv {
+                    builder.set_initial_state(MD4State { s });
+                }
+                builder.set_cache(cache);
+                App::build(builder, &mut reader).map(|(m, s)| (m, s.s))
+            }
+            _ => {
+                let mut builder = Builder::new();
+                if let Some(s) = iv {
+                    builder.set_initial_state(MD4State { s });
+                }
+                builder.set_jpeg_mode(opt.jpeg_mode);
+                builder.set_cache(cache);
+                if let Some(file) = &opt.path {
+                    match DifferentialPath::load(file) {
+                        Ok(path) => builder.set_path(path),
+                        Err(e) => {
+                            note!(structured, "======= ERROR: {}", e);
+                            return;
+                        }
+                    }
+                }
+                App::build(builder, &mut reader).map(|(m, s)| (m, s.s))
+            }
+        };
 
-        // Build
-        match builder.build() {
-            Ok((padding, m1, m2)) => {
+        match result {
+            Ok(((padding, m1, m2), state)) => {
                 // Print info
-                println!("=> Collision info");
-                println!("Created collision starting at byte offset {}", limit);
-                println!("Padding length: {} bytes", padding.len());
+                note!(structured, "=> Collision info");
+                note!(
+                    structured,
+                    "Created collision starting at byte offset {}",
+                    limit
+                );
+                note!(
+                    structured,
+                    "Search time: {:.3} seconds",
+                    start.elapsed().as_secs_f64()
+                );
+                note!(structured, "Padding length: {} bytes", padding.len());
                 if !padding.is_empty() {
-                    println!("Padding: {:02x}", padding.iter().format(""));
+                    note!(structured, "Padding: {:02x}", padding.iter().format(""));
                 }
-                println!("Message1: {:02x}", m1.iter().format(""));
-                println!("Message2: {:02x}", m2.iter().format(""));
+                note!(structured, "Message1: {:02x}", m1.iter().format(""));
+                note!(structured, "Message2: {:02x}", m2.iter().format(""));
 
                 // Write to output
-                App::write_output(&opt.output1, &opt.input, opt.offset, &padding, &m1);
-                App::write_output(&opt.output2, &opt.input, opt.offset, &padding, &m2);
+                drop(reader);
+                for (output, m) in [(output1, &m1), (output2, &m2)] {
+                    let mut writer: Box<dyn Write> = if output == Path::new("-") {
+                        Box::new(std::io::stdout())
+                    } else {
+                        Box::new(File::create(output).unwrap_or_else(|_| {
+                            panic!("failed to create output file {}", output.display())
+                        }))
+                    };
+                    App::write_output(&mut writer, &mut source, offset, &padding, m, opt.blocks)
+                        .unwrap_or_else(|_| {
+                            panic!("failed to write output file {}", output.display())
+                        });
+                }
 
-                // Print md4sum
+                // Print digests of the whole files, even when only the blocks
+                // are written, and say so then
+                note!(structured, "=> Output file:");
+                let mut sum = |m: &[u8]| {
+                    App::digest(&opt.hash, &opt.iv, |hasher| {
+                        App::write_output(hasher, &mut source, offset, &padding, m, false)
+                            .expect("failed to read input");
+                    })
+                };
+                let sum1 = sum(&m1);
+                let sum2 = sum(&m2);
+
+                let label = |output: &PathBuf| {
+                    if opt.blocks {
+                        format!("the full file whose blocks are in {}", output.display())
+                    } else {
+                        output.display().to_string()
+                    }
+                };
+                note!(structured, "{} for {}: {}", name, label(output1), sum1);
+
+                note!(structured, "{} for {}: {}", name, label(output2), sum2);
+
+                if sum1 == sum2 {
+                    note!(structured, "{} is identical.", name);
+                }
+
+                let report = CollisionReport {
+                    hash: opt.hash.clone(),
+                    offset,
+                    padding: hex::encode(&padding),
+                    message1: hex::encode(&m1),
+                    message2: hex::encode(&m2),
+                    state: [
+                        format!("{:08x}", state[0]),
+                        format!("{:08x}", state[1]),
+                        format!("{:08x}", state[2]),
+                        format!("{:08x}", state[3]),
+                    ],
+                    output1: output1.display().to_string(),
+                    output2: output2.display().to_string(),
+                    digest1: sum1.clone(),
+                    digest2: sum2.clone(),
+                    matched: sum1 == sum2,
+                };
+                match opt.format.as_str() {
+                    "json" => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+                    "toml" => print!("{}", toml::to_string(&report).unwrap()),
+                    _ => {}
+                }
+            }
+            Err(e) => note!(structured, "{:?}", e),
+        }
+    }
+
+    // Feed the prefix to the builder, then search for the collision. Also
+    // returns the chaining state the messages start from.
+    fn build<F: CollisionSearch>(
+        mut builder: Builder<F>,
+        prefix: &mut impl Read,
+    ) -> Result<(Messages, F::State), String> {
+        std::io::copy(prefix, &mut builder).unwrap();
+        let messages = builder.build().map_err(String::from)?;
+        Ok((messages, builder.state()))
+    }
+
+    fn rsync(block_size: usize, seed: u32, output1: &PathBuf, output2: &PathBuf) {
+        match rsync_block_collision(block_size) {
+            Ok((b1, b2)) => {
+                // Print info
+                println!("=> Collision info");
+                println!("Block size: {} bytes, checksum seed: {}", block_size, seed);
+
+                // Write to output
+                std::fs::write(output1, &b1).unwrap_or_else(|_| {
+                    panic!("failed to write output file {}", output1.display())
+                });
+                std::fs::write(output2, &b2).unwrap_or_else(|_| {
+                    panic!("failed to write output file {}", output2.display())
+                });
+
+                // Print strong checksums
                 println!("=> Output file:");
-                let md4sum1 = App::md4sum(&opt.output1);
-                let md4sum2 = App::md4sum(&opt.output2);
+                let sum1 = rsync_checksum(&b1, seed);
+                let sum2 = rsync_checksum(&b2, seed);
 
                 println!(
-                    "MD4Sum for {}: {}",
-                    opt.output1.display(),
-                    md4sum1
+                    "Strong checksum for {}: {:02x}",
+                    output1.display(),
+                    sum1.iter().format("")
                 );
 
                 println!(
-                    "MD4Sum for {}: {}",
-                    opt.output2.display(),
-                    md4sum2
+                    "Strong checksum for {}: {:02x}",
+                    output2.display(),
+                    sum2.iter().format("")
                 );
 
+                if sum1 == sum2 {
+                    println!("Strong checksum is identical.");
+                }
+            }
+            Err(e) => println!("======= ERROR: {}", e),
+        }
+    }
+
+    fn forge(
+        digest: &[u8; 16],
+        length: u64,
+        extension: &str,
+        is_hex: bool,
+        secret_length: &Option<RangeInclusive<u64>>,
+    ) {
+        let ext = if is_hex {
+            match hex::decode(extension) {
+                Ok(ext) => ext,
+                Err(e) => {
+                    println!("======= ERROR: invalid hex extension: {}", e);
+                    return;
+                }
+            }
+        } else {
+            extension.as_bytes().to_vec()
+        };
+
+        let forgeries = match secret_length {
+            Some(range) => forge_range(digest, length, range.clone(), &ext),
+            None => vec![forge(digest, length, &ext)],
+        };
+
+        for forgery in forgeries {
+            println!("=> Forgery for original length {}", forgery.original_len);
+            println!("Glue: {:02x}", forgery.glue.iter().format(""));
+            println!("Forged digest: {:02x}", forgery.digest.iter().format(""));
+        }
+    }
+
+    fn append(
+        offset: Option<u64>,
+        inputs: [&PathBuf; 2],
+        suffix: &PathBuf,
+        outputs: [&PathBuf; 2],
+    ) {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).unwrap_or_else(|_| panic!("failed to read file {}", path.display()))
+        };
+        let m1 = read(inputs[0]);
+        let m2 = read(inputs[1]);
+        let suffix = read(suffix);
+
+        match append_common_suffix(&m1, &m2, offset.map(|o| o as usize), &suffix) {
+            Ok((o1, o2)) => {
+                // Write to output
+                for (output, data) in outputs.iter().zip(&[o1, o2]) {
+                    std::fs::write(output, data).unwrap_or_else(|_| {
+                        panic!("failed to write output file {}", output.display())
+                    });
+                }
+
+                // Print md4sum
+                println!("=> Output file:");
+                let md4sum1 = App::md4sum(outputs[0]);
+                let md4sum2 = App::md4sum(outputs[1]);
+
+                println!("MD4Sum for {}: {}", outputs[0].display(), md4sum1);
+
+                println!("MD4Sum for {}: {}", outputs[1].display(), md4sum2);
+
                 if md4sum1 == md4sum2 {
                     println!("MD4Sum is identical.");
                 }
             }
-            Err(e) => println!("{:?}", e),
+            Err(e) => println!("======= ERROR: {}", e),
+        }
+    }
+
+    fn verify(a: &PathBuf, b: &PathBuf) {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).unwrap_or_else(|_| panic!("failed to read file {}", path.display()))
+        };
+
+        match verify(&read(a), &read(b)) {
+            Ok(v) => {
+                println!("=> Differing region");
+                println!(
+                    "First differing block: {} (byte offset {})",
+                    v.first_block,
+                    v.offset()
+                );
+                println!("Last differing block: {}", v.last_block);
+                println!(
+                    "Chaining state before: {:08x}",
+                    v.state_before.s.iter().format(" ")
+                );
+                match v.states_after {
+                    Some((s1, s2)) if s1 == s2 => {
+                        println!("Chaining state after: {:08x}", s1.s.iter().format(" "));
+                        println!("Chaining states match after the differing region.");
+                    }
+                    Some((s1, s2)) => {
+                        println!(
+                            "Chaining state after ({}): {:08x}",
+                            a.display(),
+                            s1.s.iter().format(" ")
+                        );
+                        println!(
+                            "Chaining state after ({}): {:08x}",
+                            b.display(),
+                            s2.s.iter().format(" ")
+                        );
+                        println!("Chaining states differ after the differing region.");
+                    }
+                    None => println!("Differing region ends in a partial block."),
+                }
+
+                println!(
+                    "=> Message word differences (B - A) in block {}",
+                    v.first_block
+                );
+                for (i, delta) in v.deltas.iter().enumerate() {
+                    if *delta != 0 {
+                        println!("M{}: {:08x}", i, delta);
+                    }
+                }
+                if v.wang_differential {
+                    println!(
+                        "Matches the Wang differential (M1: 2^31, M2: 2^31-2^28, M12: -2^16)."
+                    );
+                } else {
+                    println!("Does not match the Wang differential.");
+                }
+
+                println!("=> Digest");
+                if v.digests_equal {
+                    println!("MD4Sum is identical.");
+                } else {
+                    println!("MD4Sum differs.");
+                }
+            }
+            Err(e) => println!("======= ERROR: {}", e),
+        }
+    }
+
+    fn load_path(path: &Option<PathBuf>) -> Option<DifferentialPath> {
+        match path {
+            Some(file) => match DifferentialPath::load(file) {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    println!("======= ERROR: {}", e);
+                    None
+                }
+            },
+            None => Some(DifferentialPath::wang()),
+        }
+    }
+
+    fn trace(state: &[u32], format: &str, path: &Option<PathBuf>, block: &U8Block) {
+        let init = App::initial_state(state);
+        let path = match App::load_path(path) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let t = trace(&init, block, &path);
+        if format == "json" {
+            println!("{}", serde_json::to_string_pretty(&t).unwrap());
+        } else {
+            print!("{}", t.table());
+            println!("Violated conditions: {}", t.violations());
+        }
+    }
+
+    fn trace_extended(states: [&[u32]; 2], format: &str, path: &Option<PathBuf>, block: &U8Block) {
+        let mut init = ExtendedMD4State::new();
+        if !states[0].is_empty() {
+            init.left.s.copy_from_slice(states[0]);
+        }
+        if !states[1].is_empty() {
+            init.right.s.copy_from_slice(states[1]);
+        }
+        let path = match App::load_path(path) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let traces = trace_extended(&init, block, &path);
+        if format == "json" {
+            println!("{}", serde_json::to_string_pretty(&traces).unwrap());
+        } else {
+            for (name, t) in ["Left", "Right"].iter().zip(&traces) {
+                println!("{} line:", name);
+                print!("{}", t.table());
+                println!("Violated conditions: {}", t.violations());
+            }
+        }
+    }
+
+    fn complete(
+        target: &[u32],
+        path: &Option<PathBuf>,
+        keep: usize,
+        (width, expansions): (usize, usize),
+        seed: u64,
+        format: &str,
+    ) {
+        let fail = |e: &str| -> ! {
+            println!("======= ERROR: {}", e);
+            std::process::exit(1)
+        };
+        let path = App::load_path(path).unwrap_or_else(|| std::process::exit(1));
+        if keep > 48 {
+            fail("a path only has 48 steps");
+        }
+
+        let mut completion = PathCompletion::new(&path, keep);
+        completion.set_beam(width, expansions);
+        completion.set_seed(seed);
+        if !target.is_empty() {
+            let mut t = [0u32; 4];
+            t.copy_from_slice(target);
+            completion.set_target(t);
+        }
+
+        match completion.complete() {
+            Ok(found) => {
+                if format == "json" {
+                    println!("{}", found.to_json());
+                } else {
+                    print!("{}", found.to_text());
+                }
+            }
+            Err(e) => fail(e),
+        }
+    }
+
+    fn initial_state(state: &[u32]) -> MD4State {
+        let mut init = MD4State::new();
+        if !state.is_empty() {
+            init.s.copy_from_slice(state);
+        }
+        init
+    }
+
+    fn conforming(state: &[u32], path: &Option<PathBuf>, fixed: &[Vec<(usize, u8)>], count: usize) {
+        let init = App::initial_state(state);
+        let path = match App::load_path(path) {
+            Some(path) => path,
+            None => return,
+        };
+        let fixed: Vec<(usize, u8)> = fixed.iter().flatten().cloned().collect();
+
+        for _ in 0..count {
+            match conforming_message(init, &path, &fixed) {
+                Ok((m1, m2)) => println!("{} {}", hex::encode(m1), hex::encode(m2)),
+                Err(e) => {
+                    println!("======= ERROR: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn lab(steps: &[usize], attempts: u64, seed: u64) {
+        println!(
+            "{:<5} {:<26} {:>10} {:>10} {:<9} preimage",
+            "steps", "shortcut", "attempts", "seconds", "collision"
+        );
+        for &n in steps {
+            match lab(n, attempts, seed) {
+                Ok(result) => println!(
+                    "{:<5} {:<26} {:>10} {:>10.3} {:<9} {}",
+                    n,
+                    result.shortcut.describe(),
+                    result.attempts,
+                    result.elapsed.as_secs_f64(),
+                    if result.collision.is_some() {
+                        "found"
+                    } else {
+                        "-"
+                    },
+                    if result.preimage { "found" } else { "-" }
+                ),
+                Err(e) => println!("======= ERROR: {}", e),
+            }
+        }
+    }
+
+    fn compare(collisions: usize, seed: u64) {
+        println!(
+            "{:<10} {:>10} {:>12} {:>10} {:>14}",
+            "algorithm", "collisions", "attempts", "seconds", "per collision"
+        );
+        for timing in &compare_algorithms(collisions, seed) {
+            let seconds = timing.elapsed.as_secs_f64();
+            println!(
+                "{:<10} {:>10} {:>12} {:>10.3} {:>14.3}",
+                timing.algorithm,
+                timing.collisions,
+                timing.attempts,
+                seconds,
+                seconds / timing.collisions.max(1) as f64
+            );
+        }
+    }
+
+    fn batch(
+        count: u64,
+        prefix_file: &Option<PathBuf>,
+        out: &PathBuf,
+        seed: Option<u64>,
+        jobs: usize,
+    ) {
+        let prefix = match prefix_file {
+            Some(path) => std::fs::read(path).expect("failed to read prefix file"),
+            None => Vec::new(),
+        };
+        let mut file = match File::create(out) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("======= ERROR: cannot create {}: {}", out.display(), e);
+                return;
+            }
+        };
+
+        let seed = seed.unwrap_or_else(rand::random);
+        println!("=> Generating {} collisions from seed {}", count, seed);
+        let mut done = 0;
+        batch(&prefix, count, seed, jobs, |result| {
+            writeln!(file, "{}", serde_json::to_string(&result).unwrap())
+                .unwrap_or_else(|_| panic!("failed to write {}", out.display()));
+            done += 1;
+            println!(
+                "[{}/{}] seed {}: {} attempts, {:.3} seconds",
+                done, count, result.seed, result.attempts, result.time
+            );
+        });
+    }
+
+    fn state(state: &[u32], path: &Option<PathBuf>, outputs: &[PathBuf]) {
+        let init = App::initial_state(state);
+        let path = match App::load_path(path) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut finder = CollisionFinder::with_path(init, &path);
+        let (b1, b2) = loop {
+            if let Some(pair) = finder.find_batch() {
+                break pair;
+            }
+        };
+
+        println!("=> Collision info");
+        println!("Chaining state: {:08x}", init.s.iter().format(" "));
+        println!("Block1: {:02x}", b1.iter().format(""));
+        println!("Block2: {:02x}", b2.iter().format(""));
+        let (s1, s2) = (init.process_block(&b1), init.process_block(&b2));
+        println!("State after block1: {:08x}", s1.s.iter().format(" "));
+        println!("State after block2: {:08x}", s2.s.iter().format(" "));
+        if s1 == s2 {
+            println!("State is identical.");
+        }
+
+        for (output, block) in outputs.iter().zip(&[b1, b2]) {
+            std::fs::write(output, block)
+                .unwrap_or_else(|_| panic!("failed to write output file {}", output.display()));
+        }
+    }
+
+    fn bench(seed: u64, collisions: u64, tunnel_depth: Option<usize>, format: &str) {
+        let mut config = BenchConfig {
+            seed,
+            collisions,
+            ..Default::default()
+        };
+        if let Some(depth) = tunnel_depth {
+            config.tunnel_depth = depth;
+        }
+        let report = bench(&config);
+        match format {
+            "json" => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+            _ => print!("{}", report.to_text()),
+        }
+    }
+
+    fn cache(action: &CacheAction) {
+        let cache = match CollisionCache::open_default() {
+            Some(cache) => cache,
+            None => {
+                println!("======= ERROR: neither XDG_CACHE_HOME nor HOME is set");
+                return;
+            }
+        };
+
+        match action {
+            CacheAction::List => {
+                let entries = cache.list().expect("failed to read the cache directory");
+                println!("=> Cached collisions in {}", cache.dir().display());
+                for entry in &entries {
+                    println!("{}", entry.key);
+                    println!("  Message1: {:02x}", entry.m1.iter().format(""));
+                    println!("  Message2: {:02x}", entry.m2.iter().format(""));
+                }
+                println!("{} collisions", entries.len());
+            }
+            CacheAction::Clear => {
+                let count = cache.clear().expect("failed to clear the cache directory");
+                println!(
+                    "Removed {} collisions from {}",
+                    count,
+                    cache.dir().display()
+                );
+            }
+        }
+    }
+
+    fn multi(input: &PathBuf, offsets: &[Offset], prefix: &Path, select: &[String]) {
+        let data = if input == Path::new("-") {
+            let mut data = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut data)
+                .expect("failed to read stdin");
+            data
+        } else {
+            std::fs::read(input).expect("failed to read input file")
+        };
+
+        let len = data.len() as u64;
+        let mut cursor = Cursor::new(&data);
+        let offsets: Result<Vec<u64>, _> = offsets
+            .iter()
+            .map(|offset| offset.resolve(&mut cursor, len))
+            .collect();
+        let points = match offsets.and_then(|offsets| multi_collide(&data, &offsets)) {
+            Ok(points) => points,
+            Err(e) => {
+                println!("======= ERROR: {}", e);
+                return;
+            }
+        };
+
+        // Every combination unless some are selected
+        let k = points.len();
+        let choices: Vec<String> = if select.is_empty() {
+            if k > 16 {
+                println!("======= ERROR: use --select with more than 16 collisions");
+                return;
+            }
+            (0..1u32 << k)
+                .map(|i| {
+                    (0..k)
+                        .map(|j| if i >> (k - 1 - j) & 1 == 1 { '1' } else { '0' })
+                        .collect()
+                })
+                .collect()
+        } else {
+            select.to_vec()
+        };
+        if let Some(bad) = choices
+            .iter()
+            .find(|c| c.len() != k || c.chars().any(|b| b != '0' && b != '1'))
+        {
+            println!("======= ERROR: {} is not a string of {} bits", bad, k);
+            return;
+        }
+
+        println!("=> Collision info");
+        for (i, point) in points.iter().enumerate() {
+            println!(
+                "Collision {} at byte offset {}, padding length {} bytes",
+                i,
+                point.offset,
+                point.padding.len()
+            );
+            println!("Message1: {:02x}", point.m1.iter().format(""));
+            println!("Message2: {:02x}", point.m2.iter().format(""));
+        }
+
+        println!("=> Output file:");
+        let mut sums = Vec::new();
+        for choice in &choices {
+            let bits: Vec<bool> = choice.chars().map(|b| b == '1').collect();
+            let output = combine(&data, &points, &bits);
+            let mut path = prefix.as_os_str().to_os_string();
+            path.push(format!(".{}", choice));
+            let path = PathBuf::from(path);
+            std::fs::write(&path, &output)
+                .unwrap_or_else(|_| panic!("failed to write output file {}", path.display()));
+
+            let sum = hex::encode(Md4::digest(&output));
+            println!("MD4Sum for {}: {}", path.display(), sum);
+            sums.push(sum);
+        }
+        if sums.iter().all(|sum| *sum == sums[0]) {
+            println!("MD4Sum is identical for all {} outputs.", sums.len());
+        } else {
+            println!("======= ERROR: the outputs do not share one digest");
+        }
+    }
+}
+
+#[cfg(test)]
+mod app_tests {
+    use crate::app::*;
+
+    #[test]
+    fn offsets_from_the_end() {
+        for (arg, offset) in [
+            ("-64", Offset::FromEnd(64)),
+            ("-0x40", Offset::FromEnd(0x40)),
+            ("end-0x40", Offset::FromEnd(0x40)),
+            ("0x40", Offset::Absolute(0x40)),
+        ] {
+            let opt = Opt::from_iter_safe(["md4rip", "-", arg, "o1", "o2"]).unwrap();
+            assert_eq!(opt.offset, Some(offset));
+            assert_eq!(opt.output2, Some(PathBuf::from("o2")));
+        }
+
+        // Flags still work around a negative offset
+        let opt = Opt::from_iter_safe(["md4rip", "-b", "-", "-0x40", "o1", "o2"]).unwrap();
+        assert!(opt.blocks);
+        assert_eq!(opt.offset, Some(Offset::FromEnd(0x40)));
+
+        match Opt::from_iter_safe(["md4rip", "multi", "-", "0x10", "-0x40", "-o", "out"]) {
+            Ok(Opt {
+                cmd: Some(Command::Multi { offsets, .. }),
+                ..
+            }) => assert_eq!(offsets, [Offset::Absolute(0x10), Offset::FromEnd(0x40)]),
+            other => panic!("unexpected parse {:?}", other),
         }
     }
 }
@@ -0,0 +1,82 @@
+use crate::state::MD4State;
+
+/// Check that two messages share the same MD4 internal state after `offset`
+/// bytes, i.e. equal chaining values and an identical unprocessed tail
+pub fn shares_state(m1: &[u8], m2: &[u8], offset: usize) -> bool {
+    if offset > m1.len() || offset > m2.len() {
+        return false;
+    }
+
+    let mut s1 = MD4State::new();
+    let mut s2 = MD4State::new();
+    let used1 = s1.apply_blocks(&m1[..offset]);
+    let used2 = s2.apply_blocks(&m2[..offset]);
+
+    s1 == s2 && m1[used1..offset] == m2[used2..offset]
+}
+
+/// Truncate a collision pair at `offset` (the end of both messages if `None`)
+/// and append a common suffix, which keeps the MD4 digests equal
+pub fn append_common_suffix(
+    m1: &[u8],
+    m2: &[u8],
+    offset: Option<usize>,
+    suffix: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), &'static str> {
+    let offset = match offset {
+        Some(offset) => offset,
+        None if m1.len() == m2.len() => m1.len(),
+        None => return Err("inputs have different lengths, an offset is required"),
+    };
+
+    if offset > m1.len() || offset > m2.len() {
+        return Err("offset is larger than input size");
+    }
+    if !shares_state(m1, m2, offset) {
+        return Err("inputs do not share an MD4 state at the offset");
+    }
+
+    let mut o1 = m1[..offset].to_vec();
+    let mut o2 = m2[..offset].to_vec();
+    o1.extend_from_slice(suffix);
+    o2.extend_from_slice(suffix);
+    Ok((o1, o2))
+}
+
+#[cfg(test)]
+mod append_tests {
+    use crate::append::*;
+    use crate::builder::Builder;
+    use md4::{Digest, Md4};
+
+    #[test]
+    fn append_after_collision() {
+        let prefix = vec![rand::random(); 100];
+        let mut builder = Builder::new();
+        builder.input(&prefix);
+        let (padding, b1, b2) = builder.build().unwrap();
+
+        let mut m1 = [&prefix[..], &padding, &b1].concat();
+        let mut m2 = [&prefix[..], &padding, &b2].concat();
+        m1.extend_from_slice(b"old tail");
+        m2.extend_from_slice(b"old tail");
+
+        let suffix = vec![rand::random(); 1000];
+        let (o1, o2) = append_common_suffix(&m1, &m2, None, &suffix).unwrap();
+        assert_eq!(Md4::digest(&o1), Md4::digest(&o2));
+
+        let offset = prefix.len() + padding.len() + 64;
+        let (o1, o2) = append_common_suffix(&m1, &m2, Some(offset), &suffix).unwrap();
+        assert_eq!(o1.len(), offset + suffix.len());
+        assert_eq!(Md4::digest(&o1), Md4::digest(&o2));
+
+        // Inside the colliding block the states differ
+        assert!(append_common_suffix(&m1, &m2, Some(offset - 1), &suffix).is_err());
+    }
+
+    #[test]
+    fn refuse_different_messages() {
+        assert!(append_common_suffix(b"message 1", b"message 2", None, b"tail").is_err());
+        assert!(append_common_suffix(b"message", b"message", Some(8), b"tail").is_err());
+    }
+}
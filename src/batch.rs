@@ -0,0 +1,135 @@
+use crate::collision::CollisionFinder;
+use crate::common::*;
+use crate::state::MD4State;
+use md4::{Digest, Md4};
+use serde::Serialize;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+/// One collision of a batch, as written to the results file
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchResult {
+    /// Seed of the search, which finds the same pair again
+    pub seed: u64,
+    /// Chaining state before the colliding block, as hex words
+    pub state: [String; 4],
+    /// Zero bytes completing the prefix to a whole block, in hex
+    pub padding: String,
+    pub m1: String,
+    pub m2: String,
+    /// MD4 digest of prefix || padding || m1, the same for m2
+    pub digest: String,
+    /// Messages tried, tunnel neighbours and the whole successful batch included
+    pub attempts: u64,
+    /// Search time in seconds
+    pub time: f64,
+}
+
+/// Search for `count` independent collisions after `prefix`, the i-th seeded
+/// with `first_seed + i`, on `jobs` threads. Each result is passed to `emit`
+/// as soon as it is found, so their order depends on the threads.
+pub fn batch(
+    prefix: &[u8],
+    count: u64,
+    first_seed: u64,
+    jobs: usize,
+    mut emit: impl FnMut(BatchResult),
+) {
+    // Pad the prefix with zeros to a whole block, like `Builder`
+    let padding = vec![0u8; (64 - prefix.len() % 64) % 64];
+    let mut state = MD4State::new();
+    for block in [prefix, &padding].concat().chunks(64) {
+        state.apply_block(U8Block::from_slice(block));
+    }
+    let digest_prefix = Md4::new().chain([prefix, &padding].concat());
+
+    let jobs = jobs.max(1) as u64;
+    let (sender, receiver) = mpsc::channel();
+    let workers: Vec<_> = (0..jobs)
+        .map(|job| {
+            let sender = sender.clone();
+            let padding = padding.clone();
+            let digest_prefix = digest_prefix.clone();
+            thread::spawn(move || {
+                for i in (job..count).step_by(jobs as usize) {
+                    let seed = first_seed.wrapping_add(i);
+                    let result = find(state, seed, &padding, digest_prefix.clone());
+                    if sender.send(result).is_err() {
+                        return;
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    for result in receiver {
+        emit(result);
+    }
+    for worker in workers {
+        worker.join().expect("batch worker panicked");
+    }
+}
+
+fn find(state: MD4State, seed: u64, padding: &[u8], digest_prefix: Md4) -> BatchResult {
+    let start = Instant::now();
+    let mut finder = CollisionFinder::from(state);
+    finder.set_seed(seed);
+    let (m1, m2) = loop {
+        if let Some(pair) = finder.find_batch() {
+            break pair;
+        }
+    };
+    let stats = finder.stats();
+
+    BatchResult {
+        seed,
+        state: [
+            format!("{:08x}", state.s[0]),
+            format!("{:08x}", state.s[1]),
+            format!("{:08x}", state.s[2]),
+            format!("{:08x}", state.s[3]),
+        ],
+        padding: hex::encode(padding),
+        m1: hex::encode(m1),
+        m2: hex::encode(m2),
+        digest: hex::encode(digest_prefix.chain(m1).result()),
+        attempts: stats.restarts + stats.neighbours,
+        time: start.elapsed().as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use crate::batch::*;
+
+    #[test]
+    fn seeded_batch() {
+        let prefix = b"batch prefix";
+        let mut results = Vec::new();
+        batch(prefix, 3, 100, 2, |r| results.push(r));
+        results.sort_by_key(|r| r.seed);
+        assert_eq!(
+            results.iter().map(|r| r.seed).collect::<Vec<_>>(),
+            [100, 101, 102]
+        );
+
+        for r in &results {
+            let message = |m: &str| {
+                let mut data = prefix.to_vec();
+                data.extend(hex::decode(&r.padding).unwrap());
+                data.extend(hex::decode(m).unwrap());
+                hex::encode(Md4::digest(&data))
+            };
+            assert_ne!(r.m1, r.m2);
+            assert_eq!(message(&r.m1), r.digest);
+            assert_eq!(message(&r.m2), r.digest);
+        }
+
+        // The same seed finds the same pair
+        let mut again = Vec::new();
+        batch(prefix, 1, 101, 1, |r| again.push(r));
+        assert_eq!(again[0].m1, results[1].m1);
+    }
+}
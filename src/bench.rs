@@ -0,0 +1,290 @@
+use crate::collision::{CollisionFinder, DEFAULT_TUNNEL_DEPTH};
+use crate::common::*;
+use crate::lanes::{dispatch, Lanes, LANES};
+use crate::state::MD4State;
+use byteorder::{ByteOrder, LE};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::hint::black_box;
+use std::time::Instant;
+
+/// Bumped whenever a measurement is renamed or changes meaning
+pub const BENCH_FORMAT_VERSION: u32 = 1;
+
+/// Amount of work done by `bench`
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Seed of the collision search and of the hashed blocks
+    pub seed: u64,
+    /// Collisions `find_once` has to find
+    pub collisions: u64,
+    /// Attempts run to time each stage of `find_once`, in each of a few runs
+    pub stage_attempts: u64,
+    /// Blocks hashed to time `MD4State::process_block`
+    pub blocks: u64,
+    /// Passed to `CollisionFinder::set_tunnel_depth`
+    pub tunnel_depth: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> BenchConfig {
+        BenchConfig {
+            seed: 1,
+            collisions: 8,
+            stage_attempts: 50_000,
+            blocks: 1 << 20,
+            tunnel_depth: DEFAULT_TUNNEL_DEPTH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub value: f64,
+    pub unit: &'static str,
+}
+
+/// Measurements in a fixed order. Counts only depend on the config, so two
+/// runs with the same seed differ in their timings only.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub version: u32,
+    pub seed: u64,
+    pub measurements: Vec<Measurement>,
+}
+
+impl BenchReport {
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.measurements
+            .iter()
+            .find(|m| m.name == name)
+            .map(|m| m.value)
+    }
+
+    /// One `name value unit` line per measurement
+    pub fn to_text(&self) -> String {
+        let mut text = format!("version {}\nseed {}\n", self.version, self.seed);
+        for m in &self.measurements {
+            text.push_str(&format!("{:<40} {:>16.3} {}\n", m.name, m.value, m.unit));
+        }
+        text
+    }
+}
+
+// Stages of an attempt, each timed by running the attempts up to it and
+// subtracting the time taken up to the previous one
+const STAGES: [&str; 5] = [
+    "stage.message.ns",
+    "stage.first_round.ns",
+    "stage.second_round.ns",
+    "stage.message_pair.ns",
+    "stage.check.ns",
+];
+
+const STAGE_RUNS: usize = 5;
+
+fn run_stages(finder: &mut CollisionFinder, init: &MD4State, stages: usize) {
+    finder.init_message();
+    if stages > 1 && !finder.first_round() {
+        return;
+    }
+    if stages > 2 {
+        finder.second_round();
+    }
+    if stages > 3 {
+        if let Some((b1, b2)) = black_box(finder.message_pair()) {
+            if stages > 4 {
+                black_box(init.process_block(&b1) == init.process_block(&b2));
+            }
+        }
+    }
+}
+
+/// Time the MD4 collision search from the IV with the Wang path
+pub fn bench(config: &BenchConfig) -> BenchReport {
+    let init = MD4State::new();
+    let mut measurements = Vec::new();
+    let mut measure = |name, value, unit| measurements.push(Measurement { name, value, unit });
+
+    // Search until the collisions are found, one message after the other or
+    // in batches. Attempts count every message tried, neighbours included.
+    let collisions = config.collisions.max(1) as f64;
+    let search = |batch: bool, depth: usize| {
+        let mut finder = CollisionFinder::from(init);
+        finder.set_seed(config.seed);
+        finder.set_tunnel_depth(depth);
+        let start = Instant::now();
+        for _ in 0..config.collisions {
+            loop {
+                let found = if batch {
+                    finder.find_batch()
+                } else {
+                    finder.find_once()
+                };
+                if black_box(found).is_some() {
+                    break;
+                }
+            }
+        }
+        (finder.stats(), start.elapsed().as_secs_f64())
+    };
+
+    let (stats, elapsed) = search(false, config.tunnel_depth);
+    let attempts = (stats.restarts + stats.neighbours) as f64;
+    measure("find_once.collisions", config.collisions as f64, "count");
+    measure("find_once.attempts", attempts, "count");
+    measure("find_once.attempts_per_second", attempts / elapsed, "1/s");
+    measure(
+        "find_once.attempts_per_collision",
+        attempts / collisions,
+        "count",
+    );
+    measure("find_once.seconds_per_collision", elapsed / collisions, "s");
+
+    let (batch_stats, elapsed) = search(true, config.tunnel_depth);
+    let attempts = (batch_stats.restarts + batch_stats.neighbours) as f64;
+    measure("find_batch.attempts", attempts, "count");
+    measure("find_batch.attempts_per_second", attempts / elapsed, "1/s");
+    measure(
+        "find_batch.seconds_per_collision",
+        elapsed / collisions,
+        "s",
+    );
+
+    // Restarts saved by the tunnels, against the same batches without them.
+    // All tunnel statistics come from batches, so that they compare.
+    let (baseline, _) = search(true, 0);
+    let tunnels = CollisionFinder::from(init).tunnel_count();
+    measure("tunnels.count", tunnels as f64, "count");
+    measure("tunnels.depth", config.tunnel_depth as f64, "count");
+    measure(
+        "tunnels.restarts_per_collision",
+        batch_stats.restarts as f64 / collisions,
+        "count",
+    );
+    measure(
+        "tunnels.neighbours_per_collision",
+        batch_stats.neighbours as f64 / collisions,
+        "count",
+    );
+    measure(
+        "tunnels.baseline_restarts_per_collision",
+        baseline.restarts as f64 / collisions,
+        "count",
+    );
+    measure(
+        "tunnels.restart_reduction",
+        baseline.restarts as f64 / batch_stats.restarts.max(1) as f64,
+        "x",
+    );
+
+    // Stages, each timed on the same messages. The fastest of a few runs is
+    // kept, which is less disturbed by other processes.
+    let mut previous = 0.0;
+    for (stages, name) in (1..).zip(STAGES.iter()) {
+        let total = (0..STAGE_RUNS)
+            .map(|_| {
+                let mut finder = CollisionFinder::from(init);
+                finder.set_seed(config.seed);
+                let start = Instant::now();
+                for _ in 0..config.stage_attempts {
+                    run_stages(&mut finder, &init, stages);
+                }
+                start.elapsed().as_secs_f64() * 1e9 / config.stage_attempts.max(1) as f64
+            })
+            .fold(f64::INFINITY, f64::min);
+        measure(name, (total - previous).max(0.0), "ns");
+        previous = total;
+    }
+
+    // Compression function throughput
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let blocks: Vec<U8Block> = (0..64)
+        .map(|_| {
+            let mut block = U8Block::default();
+            rng.fill(&mut block[..]);
+            block
+        })
+        .collect();
+    let mut state = init;
+    let start = Instant::now();
+    for i in 0..config.blocks {
+        state = state.process_block(black_box(&blocks[i as usize % blocks.len()]));
+    }
+    black_box(state);
+    let elapsed = start.elapsed().as_secs_f64();
+    let count = config.blocks.max(1) as f64;
+    measure("process_block.ns", elapsed * 1e9 / count, "ns");
+    measure(
+        "process_block.mib_per_second",
+        count * 64.0 / elapsed / (1 << 20) as f64,
+        "MiB/s",
+    );
+
+    // The same blocks, LANES at a time
+    let lanes: Vec<[Lanes; 16]> = blocks
+        .chunks(LANES)
+        .map(|chunk| {
+            let mut data = [Lanes::default(); 16];
+            for (l, block) in chunk.iter().enumerate() {
+                for (i, word) in data.iter_mut().enumerate() {
+                    word.0[l] = LE::read_u32(&block[4 * i..]);
+                }
+            }
+            data
+        })
+        .collect();
+    let calls = config.blocks.div_ceil(LANES as u64);
+    let start = Instant::now();
+    dispatch(
+        #[inline(always)]
+        || {
+            for i in 0..calls {
+                black_box(init.process_lanes(black_box(&lanes[i as usize % lanes.len()])));
+            }
+        },
+    );
+    let elapsed = start.elapsed().as_secs_f64();
+    let count = (calls.max(1) * LANES as u64) as f64;
+    measure("process_lanes.ns", elapsed * 1e9 / count, "ns");
+    measure(
+        "process_lanes.mib_per_second",
+        count * 64.0 / elapsed / (1 << 20) as f64,
+        "MiB/s",
+    );
+
+    BenchReport {
+        version: BENCH_FORMAT_VERSION,
+        seed: config.seed,
+        measurements,
+    }
+}
+
+#[cfg(test)]
+mod bench_tests {
+    use crate::bench::*;
+
+    #[test]
+    fn seeded_counts_repeat() {
+        let config = BenchConfig {
+            seed: 7,
+            collisions: 1,
+            stage_attempts: 100,
+            blocks: 100,
+            tunnel_depth: 4,
+        };
+        let (a, b) = (bench(&config), bench(&config));
+        let names: Vec<_> = a.measurements.iter().map(|m| m.name).collect();
+        assert_eq!(
+            names,
+            b.measurements.iter().map(|m| m.name).collect::<Vec<_>>()
+        );
+        assert!(names.iter().all(|name| a.to_text().contains(name)));
+
+        assert_eq!(a.get("find_once.attempts"), b.get("find_once.attempts"));
+        assert!(a.get("find_once.attempts").unwrap() >= 1.0);
+        assert!(a.get("process_block.mib_per_second").unwrap() > 0.0);
+    }
+}
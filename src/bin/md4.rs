@@ -10,6 +10,9 @@ pub struct Opt {
     /// The input file to calculate MD4
     #[structopt(name = "PATH", parse(try_from_os_str = "parse_existing_file"))]
     path: PathBuf,
+    /// Calculate the 256-bit Extended MD4 digest instead
+    #[structopt(short = "e", long = "extended")]
+    extended: bool,
 }
 
 fn parse_existing_file(s: &OsStr) -> Result<PathBuf, OsString> {
@@ -22,6 +25,11 @@ fn parse_existing_file(s: &OsStr) -> Result<PathBuf, OsString> {
 }
 
 fn main() {
-    let md4sum = App::md4sum(&Opt::from_args().path);
+    let opt = Opt::from_args();
+    let md4sum = if opt.extended {
+        App::extended_md4sum(&opt.path)
+    } else {
+        App::md4sum(&opt.path)
+    };
     println!("{}", md4sum);
 }
@@ -1,15 +1,132 @@
+use crate::cache::CollisionCache;
 use crate::collision::CollisionFinder;
 use crate::common::*;
-use crate::state::MD4State;
+use crate::compression::{CollisionSearch, CompressionState};
+use crate::dobbertin::DobbertinCollisionFinder;
+use crate::md5_collision::MD5CollisionFinder;
+use crate::path::DifferentialPath;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-#[derive(Default)]
-pub struct Builder {
+// Padding and the two colliding messages
+pub(crate) type Messages = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+type Block<F> = GenericArray<u8, BlockSize<F>>;
+type BlockSize<F> = <<F as CollisionSearch>::State as CompressionState>::BlockSize;
+
+/// Feeds a prefix to a compression function, then searches for a collision
+/// after it with `F`. MD4 with `CollisionFinder` is the default.
+pub struct Builder<F: CollisionSearch = CollisionFinder> {
     input_bytes: u64,
-    buffer: BlockBuffer<U64>,
-    state: MD4State,
+    buffer: BlockBuffer<BlockSize<F>>,
+    state: F::State,
     timeout_sec: usize,
-    jpeg_mode: bool,
+    options: F::Options,
+    cache: Option<CollisionCache>,
+    cancel: CancelToken,
+}
+
+/// A flag stopping the searches of a `Builder` from another thread. Clones
+/// share the flag, which stays set once cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Outcome of `Search::step`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    Found(Messages),
+    /// No collision in the attempts made, step again to continue
+    Pending,
+    Cancelled,
+}
+
+/// A collision search started by `Builder::search`, which only runs while
+/// `step` is called, so that a caller can interleave it with other work
+pub struct Search<F: CollisionSearch> {
+    padding: Vec<u8>,
+    finder: F,
+    cached: Option<(Vec<u8>, Vec<u8>)>,
+    cache: Option<(CollisionCache, String)>,
+    cancel: CancelToken,
+    attempts: u64,
+}
+
+impl<F: CollisionSearch> Search<F> {
+    /// Make up to `attempts` calls to `CollisionSearch::find_messages`,
+    /// checking the cancel token before each; searches with long attempts
+    /// also check it within them. A collision from the cache is returned by
+    /// the first step without any attempt.
+    pub fn step(&mut self, attempts: u64) -> Progress {
+        if let Some((m1, m2)) = self.cached.take() {
+            return Progress::Found((self.padding.clone(), m1, m2));
+        }
+
+        for _ in 0..attempts {
+            if self.cancel.is_cancelled() {
+                return Progress::Cancelled;
+            }
+            self.attempts += 1;
+            if let Some((m1, m2)) = self.finder.find_messages() {
+                if let Some((cache, key)) = &self.cache {
+                    // The collision is still good if it cannot be stored
+                    let _ = cache.put(key, &m1, &m2);
+                }
+                return Progress::Found((self.padding.clone(), m1, m2));
+            }
+        }
+        Progress::Pending
+    }
+
+    /// Calls to `find_messages` so far
+    pub fn attempts(&self) -> u64 {
+        self.attempts
+    }
+
+    /// Step until a collision is found or the search is cancelled
+    pub fn run(&mut self) -> Result<Messages, &'static str> {
+        loop {
+            match self.step(u64::MAX) {
+                Progress::Found(messages) => return Ok(messages),
+                Progress::Cancelled => return Err("collision search cancelled"),
+                Progress::Pending => {}
+            }
+        }
+    }
+}
+
+/// `Builder` for MD4 collisions with Dobbertin's attack
+pub type DobbertinBuilder = Builder<DobbertinCollisionFinder>;
+
+/// `Builder` for MD5 collisions, whose messages are two blocks long
+pub type MD5Builder = Builder<MD5CollisionFinder>;
+
+impl<F: CollisionSearch> Default for Builder<F> {
+    fn default() -> Self {
+        Builder {
+            input_bytes: 0,
+            buffer: Default::default(),
+            state: Default::default(),
+            timeout_sec: 0,
+            options: Default::default(),
+            cache: None,
+            cancel: Default::default(),
+        }
+    }
 }
 
 impl Builder {
@@ -18,7 +135,30 @@ impl Builder {
     }
 
     pub fn set_jpeg_mode(&mut self, j: bool) {
-        self.jpeg_mode = j;
+        self.options.jpeg_mode = j;
+    }
+
+    /// Follow another differential path instead of the Wang path
+    pub fn set_path(&mut self, path: DifferentialPath) {
+        self.options.path = Some(path);
+    }
+}
+
+impl<F: CollisionSearch> Builder<F> {
+    /// Create a builder passing the given settings to the search
+    pub fn with_options(options: F::Options) -> Builder<F> {
+        Builder {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Start from `state` instead of the IV of the hash function. Input
+    /// already given is discarded.
+    pub fn set_initial_state(&mut self, state: F::State) {
+        self.input_bytes = 0;
+        self.buffer = Default::default();
+        self.state = state;
     }
 
     pub fn input<B: AsRef<[u8]>>(&mut self, input: B) {
@@ -26,14 +166,55 @@ impl Builder {
         self.input_bytes = self.input_bytes.wrapping_add(input.len() as u64);
         let self_state = &mut self.state;
         self.buffer
-            .input(input, |d: &U8Block| self_state.apply_block(d));
+            .input(input, |d: &Block<F>| self_state.apply_block(d));
+    }
+
+    /// The chaining state after the input so far, or after the padding once
+    /// `build` has run, which is the state the colliding messages start from
+    pub fn state(&self) -> F::State {
+        self.state
     }
 
     pub fn set_timeout(&mut self, seconds: usize) {
         self.timeout_sec = seconds;
     }
 
-    pub fn build(&mut self) -> Result<(Vec<u8>, U8Block, U8Block), &str> {
+    /// Look up collisions in `cache` before searching, and store the ones
+    /// found. Only searches giving a `CollisionSearch::cache_key` use it.
+    pub fn set_cache(&mut self, cache: Option<CollisionCache>) {
+        self.cache = cache;
+    }
+
+    // Whether both messages are whole blocks leading to the same state
+    fn collides(&self, m1: &[u8], m2: &[u8]) -> bool {
+        let size = Block::<F>::default().len();
+        let process = |m: &[u8]| {
+            let mut state = self.state;
+            for block in m.chunks(size) {
+                state.apply_block(Block::<F>::from_slice(block));
+            }
+            state
+        };
+        m1 != m2
+            && !m1.is_empty()
+            && m1.len().is_multiple_of(size)
+            && m2.len().is_multiple_of(size)
+            && process(m1) == process(m2)
+    }
+
+    /// Stop `build` and the searches of this builder when `token` is
+    /// cancelled. Each builder starts with a token of its own.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Pad the input with zeros to a whole block, then return the search for
+    /// a collision after it, which `build` runs to the end
+    pub fn search(&mut self) -> Search<F> {
         let mut padding = Vec::new();
 
         // if buffer has remaining, pad with zeros
@@ -43,23 +224,62 @@ impl Builder {
             padding.extend_from_slice(&vec![0u8; remaining]);
             let self_state = &mut self.state;
             self.buffer
-                .input(&padding, |d: &U8Block| self_state.apply_block(d));
+                .input(&padding, |d: &Block<F>| self_state.apply_block(d));
         }
 
-        let mut finder = CollisionFinder::from(self.state);
-        finder.set_jpeg_mode(self.jpeg_mode);
-        loop {
-            match finder.find_once() {
-                Some((m1, m2)) => {
-                    return Ok((padding, m1, m2));
+        // A cached pair is checked, so a stale or damaged entry is searched again
+        let key = F::cache_key(&self.state, &self.options);
+        let cache = self.cache.clone().zip(key);
+        let cached = cache
+            .as_ref()
+            .and_then(|(cache, key)| cache.get(key))
+            .filter(|(m1, m2)| self.collides(m1, m2));
+
+        let mut finder = F::new(self.state, &self.options);
+        finder.set_cancel_token(self.cancel.clone());
+        Search {
+            padding,
+            finder,
+            cached,
+            cache,
+            cancel: self.cancel.clone(),
+            attempts: 0,
+        }
+    }
+
+    pub fn build(&mut self) -> Result<Messages, &str> {
+        self.search().run()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F> Builder<F>
+where
+    F: CollisionSearch + Send + 'static,
+{
+    /// `build` on the blocking thread pool of tokio. Dropping the future, as
+    /// when the client of a request goes away, cancels the builder's token,
+    /// which stops the search at its next attempt.
+    pub async fn build_async(&mut self) -> Result<Messages, &'static str> {
+        struct CancelOnDrop(Option<CancelToken>);
+
+        impl Drop for CancelOnDrop {
+            fn drop(&mut self) {
+                if let Some(token) = self.0.take() {
+                    token.cancel();
                 }
-                None => continue,
             }
         }
+
+        let mut search = self.search();
+        let mut guard = CancelOnDrop(Some(self.cancel.clone()));
+        let result = tokio::task::spawn_blocking(move || search.run()).await;
+        guard.0 = None;
+        result.map_err(|_| "collision search panicked")?
     }
 }
 
-impl io::Write for Builder {
+impl<F: CollisionSearch> io::Write for Builder<F> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.input(buf);
         Ok(buf.len())
@@ -72,10 +292,87 @@ impl io::Write for Builder {
 
 #[cfg(test)]
 mod builder_tests {
-    use crate::builder::Builder;
+    use crate::builder::{Builder, CancelToken, MD5Builder, Progress};
+    use crate::cache::CollisionCache;
+    use crate::common::*;
+    use crate::compression::{CollisionSearch, CompressionState};
+    use crate::dobbertin::DobbertinCollisionFinder;
+    use crate::md5_collision::MD5CollisionFinder;
+    use crate::md5_state::MD5State;
+    use crate::state::MD4State;
+    use std::thread;
+    use std::time::{Duration, Instant};
     use itertools::Itertools;
     use md4::{Digest, Md4};
-    use rand;
+
+    // A toy compression function on 16-byte blocks which xors them into the
+    // state, so that swapping two blocks always gives a collision
+    #[derive(Copy, Clone, Default, Debug, PartialEq)]
+    struct XorState([u8; 16]);
+
+    impl CompressionState for XorState {
+        type BlockSize = U16;
+
+        fn apply_block(&mut self, input: &GenericArray<u8, U16>) {
+            for (s, b) in self.0.iter_mut().zip(input) {
+                *s ^= b;
+            }
+        }
+    }
+
+    struct Swap;
+
+    impl CollisionSearch for Swap {
+        type State = XorState;
+        type Options = ();
+
+        fn new(_: XorState, _: &()) -> Swap {
+            Swap
+        }
+
+        fn find_messages(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+            let (a, b) = ([1u8; 16], [2u8; 16]);
+            Some(([a, b].concat(), [b, a].concat()))
+        }
+    }
+
+    // A search which never succeeds, to be stopped by its token
+    struct Never;
+
+    impl CollisionSearch for Never {
+        type State = XorState;
+        type Options = ();
+
+        fn new(_: XorState, _: &()) -> Never {
+            Never
+        }
+
+        fn find_messages(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+            None
+        }
+    }
+
+    #[test]
+    fn build_with_other_search() {
+        let mut builder = Builder::<Swap>::default();
+        builder.input([7u8; 20]);
+        let (padding, m1, m2) = builder.build().unwrap();
+
+        // The prefix is padded to the 16-byte block size
+        assert_eq!(padding.len(), 12);
+        assert_eq!(m1.len(), 32);
+        assert_ne!(m1, m2);
+
+        let state = |m: &[u8]| {
+            let mut state = XorState::default();
+            let message = [&[7u8; 20][..], &padding, m].concat();
+            for block in message.chunks(16) {
+                state.apply_block(GenericArray::from_slice(block));
+            }
+            state
+        };
+        assert_eq!(state(&m1), state(&m2));
+    }
 
     #[test]
     fn build_without_prefix() {
@@ -89,8 +386,8 @@ mod builder_tests {
                 let mut hasher1 = Md4::new();
                 let mut hasher2 = Md4::new();
 
-                hasher1.input(&m1);
-                hasher2.input(&m2);
+                hasher1.input(m1);
+                hasher2.input(m2);
 
                 assert_eq!(hasher1.result(), hasher2.result())
             }
@@ -117,15 +414,141 @@ mod builder_tests {
 
                 hasher1.input(&prefix);
                 hasher1.input(&padding);
-                hasher1.input(&m1);
+                hasher1.input(m1);
 
                 hasher2.input(&prefix);
                 hasher2.input(&padding);
-                hasher2.input(&m2);
+                hasher2.input(m2);
 
                 assert_eq!(hasher1.result(), hasher2.result())
             }
             Err(e) => println!("{:?}", e),
         }
     }
+
+    #[test]
+    fn build_with_cache() {
+        let dir = std::env::temp_dir().join(format!("md4rip-builder-test-{}", std::process::id()));
+        let cache = CollisionCache::new(&dir);
+        let build = |prefix: &[u8]| {
+            let mut builder = Builder::new();
+            builder.set_cache(Some(cache.clone()));
+            builder.input(prefix);
+            builder.build().unwrap()
+        };
+
+        let first = build(b"cached prefix");
+        let entries = cache.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].key.starts_with("md4-wang-"));
+        assert_eq!(build(b"cached prefix"), first);
+
+        // A damaged entry is replaced by a new search
+        cache.put(&entries[0].key, &[0u8; 64], &[0u8; 64]).unwrap();
+        let (_, m1, m2) = build(b"cached prefix");
+        let digest = |m: &[u8]| Md4::digest(&[&b"cached prefix"[..], &first.0, m].concat());
+        assert_eq!(digest(&m1), digest(&m2));
+        assert_eq!(cache.get(&entries[0].key), Some((m1, m2)));
+
+        cache.clear().unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn build_from_initial_state() {
+        let init = MD4State {
+            s: [0x0123_4567, 0x89ab_cdef, 0xfedc_ba98, 0x7654_3210],
+        };
+        let mut builder = Builder::new();
+        builder.input([1u8; 10]);
+        builder.set_initial_state(init);
+        builder.input([7u8; 64]);
+        let (padding, m1, m2) = builder.build().unwrap();
+        assert!(padding.is_empty());
+
+        let mut state = init;
+        state.apply_block(GenericArray::from_slice(&[7u8; 64]));
+        assert_eq!(builder.state(), state);
+        assert_eq!(
+            state.process_block(GenericArray::from_slice(&m1)),
+            state.process_block(GenericArray::from_slice(&m2))
+        );
+    }
+
+    #[test]
+    fn step_and_cancel() {
+        let mut builder = Builder::<Swap>::default();
+        builder.input([7u8; 20]);
+        let mut search = builder.search();
+        match search.step(1) {
+            Progress::Found((padding, m1, _)) => assert_eq!((padding.len(), m1.len()), (12, 32)),
+            progress => panic!("unexpected {:?}", progress),
+        }
+
+        let mut builder = Builder::<Never>::default();
+        let token = CancelToken::new();
+        builder.set_cancel_token(token.clone());
+        let mut search = builder.search();
+        assert_eq!(search.step(100), Progress::Pending);
+        assert_eq!(search.attempts(), 100);
+
+        token.cancel();
+        assert_eq!(search.step(100), Progress::Cancelled);
+        assert_eq!(search.attempts(), 100);
+        assert!(builder.cancel_token().is_cancelled());
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn cancel_within_attempts() {
+        // A cancelled token ends an attempt before it finds anything
+        let token = CancelToken::new();
+        token.cancel();
+        let mut md5 = MD5CollisionFinder::new(MD5State::new(), &());
+        md5.set_cancel_token(token.clone());
+        assert_eq!(md5.find_messages(), None);
+        let mut dobbertin = DobbertinCollisionFinder::new(MD4State::new(), &());
+        dobbertin.set_cancel_token(token);
+        assert_eq!(dobbertin.find_messages(), None);
+
+        // An MD5 build stops soon after its token is cancelled from another
+        // thread
+        let mut builder = MD5Builder::default();
+        let token = builder.cancel_token();
+        let start = Instant::now();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        });
+        assert!(builder.build().is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn build_async_cancelled_on_drop() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let mut builder = Builder::new();
+        builder.input(b"async prefix");
+        let (padding, m1, m2) = runtime.block_on(builder.build_async()).unwrap();
+        let digest = |m: &[u8]| Md4::digest(&[&b"async prefix"[..], &padding, m].concat());
+        assert_eq!(digest(&m1), digest(&m2));
+        assert!(!builder.cancel_token().is_cancelled());
+
+        // Dropping the future, as a server does when its client disconnects,
+        // stops the search running on the blocking pool
+        let mut builder = Builder::<Never>::default();
+        let token = builder.cancel_token();
+        let task = runtime.spawn(async move { builder.build_async().await });
+        runtime.block_on(async {
+            tokio::task::yield_now().await;
+            task.abort();
+            assert!(task.await.unwrap_err().is_cancelled());
+        });
+        assert!(token.is_cancelled());
+    }
 }
@@ -0,0 +1,138 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A directory of collisions found earlier, one file per key holding both
+/// messages as hex lines
+#[derive(Debug, Clone)]
+pub struct CollisionCache {
+    dir: PathBuf,
+}
+
+/// A collision read back from the cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub key: String,
+    pub m1: Vec<u8>,
+    pub m2: Vec<u8>,
+}
+
+const EXTENSION: &str = "collision";
+
+impl CollisionCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> CollisionCache {
+        CollisionCache { dir: dir.into() }
+    }
+
+    /// `$XDG_CACHE_HOME/md4rip`, or `~/.cache/md4rip` when it is unset
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("md4rip"))
+    }
+
+    /// The cache in the default directory
+    pub fn open_default() -> Option<CollisionCache> {
+        CollisionCache::default_dir().map(CollisionCache::new)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn file(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension(EXTENSION)
+    }
+
+    fn read(file: &Path) -> Option<(Vec<u8>, Vec<u8>)> {
+        let text = fs::read_to_string(file).ok()?;
+        let mut lines = text.lines();
+        let m1 = hex::decode(lines.next()?).ok()?;
+        let m2 = hex::decode(lines.next()?).ok()?;
+        Some((m1, m2))
+    }
+
+    /// The messages stored under `key`, if any could be read
+    pub fn get(&self, key: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+        CollisionCache::read(&self.file(key))
+    }
+
+    /// Store the messages under `key`. The file is written under another
+    /// name and then renamed, so concurrent readers never see half of it.
+    pub fn put(&self, key: &str, m1: &[u8], m2: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let temp = self.dir.join(format!(".{}.{}", key, std::process::id()));
+        fs::write(&temp, format!("{}\n{}\n", hex::encode(m1), hex::encode(m2)))?;
+        fs::rename(&temp, self.file(key))
+    }
+
+    /// Every readable entry, sorted by key. A missing directory is empty.
+    pub fn list(&self) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        for file in self.files()? {
+            let key = match file.file_stem().and_then(|s| s.to_str()) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+            if let Some((m1, m2)) = CollisionCache::read(&file) {
+                entries.push(CacheEntry { key, m1, m2 });
+            }
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(entries)
+    }
+
+    /// Remove every entry, returning how many there were
+    pub fn clear(&self) -> io::Result<usize> {
+        let files = self.files()?;
+        for file in &files {
+            fs::remove_file(file)?;
+        }
+        Ok(files.len())
+    }
+
+    fn files(&self) -> io::Result<Vec<PathBuf>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut files = Vec::new();
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == EXTENSION) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use crate::cache::*;
+
+    #[test]
+    fn put_list_clear() {
+        let dir = std::env::temp_dir().join(format!("md4rip-cache-test-{}", std::process::id()));
+        let cache = CollisionCache::new(&dir);
+        assert_eq!(cache.list().unwrap(), []);
+        assert_eq!(cache.get("a"), None);
+
+        cache.put("b", &[1, 2], &[3, 4]).unwrap();
+        cache.put("a", &[5], &[6]).unwrap();
+        cache.put("a", &[7], &[8]).unwrap();
+        assert_eq!(cache.get("a"), Some((vec![7], vec![8])));
+
+        // Unreadable entries are skipped
+        fs::write(dir.join("c.collision"), "not hex").unwrap();
+        let keys: Vec<_> = cache.list().unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, ["a", "b"]);
+
+        assert_eq!(cache.clear().unwrap(), 3);
+        assert_eq!(cache.list().unwrap(), []);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
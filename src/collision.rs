@@ -1,65 +1,283 @@
 use crate::common::*;
+use crate::compression::CollisionSearch;
+use crate::path::{BitCondition, DifferentialPath};
 use crate::state::MD4State;
 use byteorder::{ByteOrder, LE};
-use lazy_static::lazy_static;
-use rand;
+use md4::{Digest, Md4};
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+
+/// Settings of the `CollisionFinder`s a `Builder` creates
+#[derive(Default)]
+pub struct FinderOptions {
+    pub jpeg_mode: bool,
+    /// Follow this path instead of the Wang path
+    pub path: Option<DifferentialPath>,
+    /// Seed the random messages, for a reproducible search
+    pub seed: Option<u64>,
+}
 
 pub struct CollisionFinder {
     init: MD4State,
     state: MD4State,
     data: U32Block,
     jpeg_mode: bool,
+    fixed_mask: U32Block,
+    fixed_value: U32Block,
+    differences: Vec<(usize, u32)>,
+    conditions: Vec<Vec<(usize, Kind)>>,
+    rng: StdRng,
+    tunnels: Vec<(usize, u32)>,
+    tunnel_depth: usize,
+    stats: SearchStats,
+}
+
+/// Neighbours of a failed message tried through the tunnels by default
+pub const DEFAULT_TUNNEL_DEPTH: usize = 4;
+
+/// What a `CollisionFinder` has tried since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchStats {
+    /// Messages drawn at random, then built to follow round 1, a5 and d5
+    pub restarts: u64,
+    /// Messages reached from those by flipping tunnel bits
+    pub neighbours: u64,
+    /// Restarts which followed round 1 and kept the fixed bytes
+    pub candidates: u64,
+    pub collisions: u64,
 }
 
+// Conditions enforced on the first message while searching
 enum Kind {
     Equal,
+    Equal2,
+    NotEqual,
+    NotEqual2,
     Zero,
     One,
 }
-use crate::collision::Kind::Equal;
+use crate::lanes::{self, Lanes, Word, LANES};
 use crate::ops::{op1, op1_t, op2, op2_t};
 use Kind::*;
 
-// Constraints for round 1 & 2
-lazy_static! {
-    static ref CONSTRAINTS_R1: [Vec<(usize, Kind)>; 16] = [
-        /* a1 */ vec![(6, Equal)],
-        /* d1 */ vec![(6, Zero), (7, Equal), (10, Equal)],
-        /* c1 */ vec![(6, One), (7, One), (10, Zero), (25, Equal)],
-        /* b1 */ vec![(6, One), (7, Zero), (10, Zero), (25, Zero)],
-
-        /* a2 */ vec![(7, One), (10, One), (25, Zero), (13, Equal)],
-        /* d2 */ vec![(13, Zero), (18, Equal), (19, Equal), (20, Equal),  (21, Equal), (25, One)],
-        /* c2 */ vec![(12, Equal), (13, Zero), (14, Equal), (18, Zero), (19, Zero), (20, One), (21, Zero)],
-        /* b2 */ vec![(12, One), (13, One), (14, Zero), (16, Equal), (18, Zero), (19, Zero), (20, Zero), (21, Zero)],
-
-        /* a3 */ vec![(12, One), (13, One), (14, One), (16, Zero), (18, Zero), (19, Zero), (20, Zero), (22, Equal), (21, One), (25, Equal)],
-        /* d3 */ vec![(12, One), (13, One), (14, One), (16, Zero), (19, Zero), (20, One), (21, One), (22, Zero), (25, One), (29, Equal)],
-        /* c3 */ vec![(16, One), (19, Zero), (20, Zero), (21, Zero), (22, Zero), (25, Zero), (29, One), (31, Equal)],
-        /* b3 */ vec![(19, Zero), (20, One), (21, One), (22, Equal), (25, One), (29, Zero), (31, Zero)],
-
-        /* a4 */ vec![(22, Zero), (25, Zero), (26, Equal), (28, Equal), (29, One), (31, Zero)],
-        /* d4 */ vec![(22, Zero), (25, Zero), (26, One), (28, One), (29, Zero), (31, One)],
-        /* c4 */ vec![(18, Equal), (22, One), (25, One), (26, Zero), (28, Zero), (29, Zero)],
-        /* b4 */ vec![(18, Zero), (25, Equal), (26, One), (28, One), (29, Zero), (31, Equal)]
-    ];
-
-    static ref CONSTRAINTS_A5: [(usize, Kind, usize); 5] = [
-        (18, Equal, 2), (25, One, 0), (26, Zero, 0), (28, One, 0), (31, One, 0)
-    ];
-
-    static ref CONSTRAINTS_D5: [(usize, Kind, usize); 5] = [
-        (18, Equal, 0), (25, Equal, 1), (26, Equal, 1), (28, Equal, 1), (31, Equal, 1)
-    ];
+/// Message word differences (M' - M) of the Wang differential
+pub const WANG_DIFFERENTIAL: [(usize, u32); 3] = [
+    (1, 1 << 31),
+    (2, (1 << 31) - (1 << 28)),
+    (12, 0u32.wrapping_sub(1 << 16)),
+];
+
+/// First message of the colliding pair Wang et al. published for the
+/// differential, as words; the second adds `WANG_DIFFERENTIAL`
+#[cfg(test)]
+pub(crate) const WANG_MESSAGE: [u32; 16] = [
+    0x4d7a9c83, 0x56cb927a, 0xb9d5a578, 0x57a7a5ee, 0xde748a3c, 0xdcc366b3, 0xb683a020, 0x3b2a5d9f,
+    0xc69d71b3, 0xf9e99198, 0xd79f805e, 0xa63bb2e8, 0x45dd8e31, 0x97e31fe5, 0x2794bf08, 0xb9e8c3e9,
+];
+
+/// The published pair as blocks
+#[cfg(test)]
+pub(crate) fn wang_pair() -> (U8Block, U8Block) {
+    let mut m2 = WANG_MESSAGE;
+    for &(i, delta) in &WANG_DIFFERENTIAL {
+        m2[i] = m2[i].wrapping_add(delta);
+    }
+    let (mut b1, mut b2) = (U8Block::default(), U8Block::default());
+    LE::write_u32_into(&WANG_MESSAGE, &mut b1);
+    LE::write_u32_into(&m2, &mut b2);
+    (b1, b2)
+}
+
+const FIRST_ROUND_SHIFT: [u32; 4] = [3, 7, 11, 19];
+
+// Flip one bit of a round 1 step value, then correct the message words of the
+// next four steps, which use it, so that no other step value changes. Steps
+// are numbered from 0, and `data` is the message built by the search.
+#[inline(always)]
+fn flip_step_bit<W: Word>(init: [W; 4], data: &mut [W], step: usize, digit: u32) {
+    // q[j + 4] is the value written by step j; q[0..4] holds a, d, c, b
+    let [a, b, c, d] = init;
+    let mut q = [a, d, c, b, a, a, a, a, a, a, a, a, a, a, a, a, a, a, a, a];
+    let last = (step + 4).min(15);
+    for j in 0..=last {
+        q[j + 4] = op1(
+            q[j],
+            q[j + 3],
+            q[j + 2],
+            q[j + 1],
+            data[j],
+            FIRST_ROUND_SHIFT[j % 4],
+        );
+    }
+
+    q[step + 4] = q[step + 4] ^ W::splat(1 << digit);
+    for j in step..=last {
+        data[j] = op1_t(
+            q[j + 4],
+            FIRST_ROUND_SHIFT[j % 4],
+            q[j],
+            q[j + 3],
+            q[j + 2],
+            q[j + 1],
+        );
+    }
+}
+
+// Bits of step values 5 to 11 free of conditions, and not compared with by the
+// next two steps. Flipping one with `flip_step_bit` only changes m5..m15, so
+// round 1, a5 and d5 still follow the path.
+fn tunnels(path: &DifferentialPath) -> Vec<(usize, u32)> {
+    let mut tunnels = Vec::new();
+    for step in 5..12 {
+        for digit in 0..32 {
+            if path.steps[step][digit] == BitCondition::Free
+                && !matches!(
+                    path.steps[step + 1][digit],
+                    BitCondition::Prev | BitCondition::NotPrev
+                )
+                && !matches!(
+                    path.steps[step + 2][digit],
+                    BitCondition::Prev2 | BitCondition::NotPrev2
+                )
+            {
+                tunnels.push((step, digit as u32));
+            }
+        }
+    }
+    tunnels
+}
+
+// Apply conditions to the step value v, given the two previous step values
+#[inline(always)]
+fn adjust<W: Word>(mut v: W, conditions: &[(usize, Kind)], prev: W, prev2: W) -> W {
+    for (digit, kind) in conditions {
+        let bit = W::splat(1u32 << digit);
+        match kind {
+            Equal => v = v ^ ((v ^ prev) & bit),
+            Equal2 => v = v ^ ((v ^ prev2) & bit),
+            NotEqual => v = v ^ ((v ^ !prev) & bit),
+            NotEqual2 => v = v ^ ((v ^ !prev2) & bit),
+            Zero => v = v & !bit,
+            One => v = v | bit,
+        }
+    }
+    v
+}
+
+// Adjust a5 through m0, then correct m1..m4 so that a1..a2 stay unchanged.
+// `q` holds the state after round 1, `init` the state before it.
+#[inline(always)]
+fn correct_a5<W: Word>(init: [W; 4], q: &mut [W; 4], data: &mut [W], conditions: &[(usize, Kind)]) {
+    // Compute a5
+    let mut a5 = op2(q[0], q[1], q[2], q[3], data[0], 3);
+
+    // Adjust a5
+    a5 = adjust(a5, conditions, q[1], q[2]);
+
+    // Compute new m0 from adjusted a5
+    let m0 = op2_t(a5, 3, q[0], q[1], q[2], q[3]);
+
+    // Compute original a1..a2
+    let [a0, b0, c0, d0] = init;
+    let a1 = op1(a0, b0, c0, d0, data[0], 3);
+    let d1 = op1(d0, a1, b0, c0, data[1], 7);
+    let c1 = op1(c0, d1, a1, b0, data[2], 11);
+    let b1 = op1(b0, c1, d1, a1, data[3], 19);
+    let a2 = op1(a1, b1, c1, d1, data[4], 3);
+
+    // Compute new a1 from m0
+    let a1_ = op1(a0, b0, c0, d0, m0, 3);
+
+    // Update m0..m4
+    data[0] = m0;
+    data[1] = op1_t(d1, 7, d0, a1_, b0, c0);
+    data[2] = op1_t(c1, 11, c0, d1, a1_, b0);
+    data[3] = op1_t(b1, 19, b0, c1, d1, a1_);
+    data[4] = op1_t(a2, 3, a1_, b1, c1, d1);
+
+    // Write new a5
+    q[0] = a5;
+}
+
+// Adjust d5 through m4, then correct m5..m8 so that a2..a3 stay unchanged
+#[inline(always)]
+fn correct_d5<W: Word>(init: [W; 4], q: &mut [W; 4], data: &mut [W], conditions: &[(usize, Kind)]) {
+    // Compute d5
+    let mut d5 = op2(q[3], q[0], q[1], q[2], data[4], 5);
+
+    // Adjust d5
+    d5 = adjust(d5, conditions, q[0], q[1]);
+
+    // Compute new m4 from adjusted d5
+    let m4 = op2_t(d5, 5, q[3], q[0], q[1], q[2]);
+
+    // Compute original a1..a3
+    let [a0, b0, c0, d0] = init;
+    let a1 = op1(a0, b0, c0, d0, data[0], 3);
+    let d1 = op1(d0, a1, b0, c0, data[1], 7);
+    let c1 = op1(c0, d1, a1, b0, data[2], 11);
+    let b1 = op1(b0, c1, d1, a1, data[3], 19);
+    let a2 = op1(a1, b1, c1, d1, data[4], 3);
+    let d2 = op1(d1, a2, b1, c1, data[5], 7);
+    let c2 = op1(c1, d2, a2, b1, data[6], 11);
+    let b2 = op1(b1, c2, d2, a2, data[7], 19);
+    let a3 = op1(a2, b2, c2, d2, data[8], 3);
+
+    // Compute new a2 from m4
+    let a2_ = op1(a1, b1, c1, d1, m4, 3);
+
+    // Update m4..m8
+    data[4] = m4;
+    data[5] = op1_t(d2, 7, d1, a2_, b1, c1);
+    data[6] = op1_t(c2, 11, c1, d2, a2_, b1);
+    data[7] = op1_t(b2, 19, b1, c2, d2, a2_);
+    data[8] = op1_t(a3, 3, a2_, b2, c2, d2);
+
+    // Write new d5
+    q[3] = d5;
 }
 
 impl CollisionFinder {
     pub fn from(state: MD4State) -> CollisionFinder {
+        CollisionFinder::with_path(state, &DifferentialPath::wang())
+    }
+
+    /// Create a finder following another differential path. Conditions are
+    /// enforced on round 1 and on a5, d5; later steps are left to chance.
+    pub fn with_path(state: MD4State, path: &DifferentialPath) -> CollisionFinder {
+        let conditions = path.steps[..18]
+            .iter()
+            .map(|step| {
+                (0..32)
+                    .filter_map(|digit| {
+                        let kind = match step[digit] {
+                            BitCondition::Zero | BitCondition::Up => Zero,
+                            BitCondition::One | BitCondition::Down => One,
+                            BitCondition::Prev => Equal,
+                            BitCondition::Prev2 => Equal2,
+                            BitCondition::NotPrev => NotEqual,
+                            BitCondition::NotPrev2 => NotEqual2,
+                            BitCondition::Free | BitCondition::Differ => return None,
+                        };
+                        Some((digit, kind))
+                    })
+                    .collect()
+            })
+            .collect();
+
         CollisionFinder {
             init: state,
             state: Default::default(),
             data: Default::default(),
             jpeg_mode: false,
+            fixed_mask: Default::default(),
+            fixed_value: Default::default(),
+            differences: path.message_differences.clone(),
+            conditions,
+            rng: StdRng::from_entropy(),
+            tunnels: tunnels(path),
+            tunnel_depth: DEFAULT_TUNNEL_DEPTH,
+            stats: Default::default(),
         }
     }
 
@@ -67,199 +285,439 @@ impl CollisionFinder {
         self.jpeg_mode = j;
     }
 
-    fn first_round_single_step(&mut self, step: usize, s: usize, shift: u32) {
-        // Calculate chaining variable
-        let mut v = op1(
-            self.state.s[s % 4],
-            self.state.s[(s + 1) % 4],
-            self.state.s[(s + 2) % 4],
-            self.state.s[(s + 3) % 4],
-            self.data[step],
-            shift,
-        );
+    /// Draw the random messages from a generator seeded with `seed`, so that
+    /// the same seed finds the same collision
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
 
-        // Adjust chaining variable
-        for (digit, kind) in &CONSTRAINTS_R1[step] {
-            match kind {
-                Equal => v ^= (v ^ self.state.s[(s + 1) % 4]) & (1u32 << digit),
-                Zero => v &= !(1u32 << digit),
-                One => v |= 1u32 << digit,
-            }
+    /// After a message following the path up to a5, d5 fails to collide, try
+    /// up to `depth` more built from it by flipping tunnel bits, which keep it
+    /// on the path, before drawing a new one. 0 disables the tunnels.
+    pub fn set_tunnel_depth(&mut self, depth: usize) {
+        self.tunnel_depth = depth;
+    }
+
+    /// Number of tunnel bits of the path
+    pub fn tunnel_count(&self) -> usize {
+        self.tunnels.len()
+    }
+
+    pub fn stats(&self) -> SearchStats {
+        self.stats
+    }
+
+    // Neighbours tried, no more than there are combinations of tunnel bits.
+    // The k-th one flips tunnel trailing_zeros(k), which visits them all in
+    // Gray code order.
+    fn neighbours(&self) -> usize {
+        match 1usize.checked_shl(self.tunnels.len() as u32) {
+            Some(combinations) => self.tunnel_depth.min(combinations - 1),
+            None => self.tunnel_depth,
         }
+    }
 
-        // Adjust data
-        self.data[step] = op1_t(
-            v,
-            shift,
-            self.state.s[s % 4],
-            self.state.s[(s + 1) % 4],
-            self.state.s[(s + 2) % 4],
-            self.state.s[(s + 3) % 4],
-        );
+    /// Only accept messages whose bytes at the given offsets (in the first
+    /// message of the pair) have the given values. Bytes 36..64 are cheap to
+    /// fix; earlier ones may be rewritten by the second round corrections,
+    /// which makes each of them cost up to a factor of 2^8.
+    pub fn set_fixed_bytes(&mut self, fixed: &[(usize, u8)]) -> Result<(), &'static str> {
+        if fixed.iter().any(|&(offset, _)| offset >= 64) {
+            return Err("fixed byte offset is outside the block");
+        }
+
+        self.fixed_mask = Default::default();
+        self.fixed_value = Default::default();
+        for &(offset, byte) in fixed {
+            let shift = 8 * (offset % 4);
+            self.fixed_mask[offset / 4] |= 0xFF << shift;
+            self.fixed_value[offset / 4] &= !(0xFF << shift);
+            self.fixed_value[offset / 4] |= u32::from(byte) << shift;
+        }
+        Ok(())
+    }
+
+    // Returns false if the fixed bytes of the message word cannot be kept
+    fn first_round_single_step(&mut self, step: usize, s: usize, shift: u32) -> bool {
+        let mut v;
+        let mut attempts = 0;
+        loop {
+            // Calculate chaining variable
+            v = op1(
+                self.state.s[s % 4],
+                self.state.s[(s + 1) % 4],
+                self.state.s[(s + 2) % 4],
+                self.state.s[(s + 3) % 4],
+                self.data[step],
+                shift,
+            );
+
+            // Adjust chaining variable
+            v = adjust(
+                v,
+                &self.conditions[step],
+                self.state.s[(s + 1) % 4],
+                self.state.s[(s + 2) % 4],
+            );
+
+            // Adjust data
+            self.data[step] = op1_t(
+                v,
+                shift,
+                self.state.s[s % 4],
+                self.state.s[(s + 1) % 4],
+                self.state.s[(s + 2) % 4],
+                self.state.s[(s + 3) % 4],
+            );
+
+            // Retry with another word until the fixed bytes survive
+            if self.data[step] & self.fixed_mask[step] == self.fixed_value[step] {
+                break;
+            }
+            attempts += 1;
+            if attempts == 16 {
+                return false;
+            }
+            self.data[step] =
+                (self.rng.gen::<u32>() & !self.fixed_mask[step]) | self.fixed_value[step];
+        }
 
         // Write v
         self.state.s[s % 4] = v;
+        true
     }
 
-    fn second_round_a5(&mut self) {
-        // Compute a5
-        let mut a5 = op2(
-            self.state.s[0],
-            self.state.s[1],
-            self.state.s[2],
-            self.state.s[3],
-            self.data[0],
-            3,
-        );
+    fn message_filter(&self, b1: &U8Block) -> bool {
+        // bytes chosen by the caller
+        (0..16).all(|i| LE::read_u32(&b1[4 * i..]) & self.fixed_mask[i] == self.fixed_value[i])
+            // specific filter for jpeg_mode
+            && if self.jpeg_mode { self.data[1] & 0x00FF_FFFF == 0x0001_FEFF } else { true }
+    }
 
-        // Adjust a5
-        for (digit, kind, pos) in CONSTRAINTS_A5.iter() {
-            match kind {
-                Equal => a5 ^= (a5 ^ self.state.s[*pos]) & (1u32 << digit),
-                Zero => a5 &= !(1u32 << digit),
-                One => a5 |= 1u32 << digit,
+    pub fn find_once(&mut self) -> Option<(U8Block, U8Block)> {
+        self.stats.restarts += 1;
+        let (b1, b2) = self.find_candidate()?;
+        self.stats.candidates += 1;
+        if self.init.process_block(&b1) == self.init.process_block(&b2) {
+            self.stats.collisions += 1;
+            return Some((b1, b2));
+        }
+
+        // Explore the neighbourhood of the message before drawing a new one
+        let mut data = U32Block::default();
+        LE::read_u32_into(&b1, &mut data);
+        for k in 1..=self.neighbours() {
+            let (step, digit) = self.tunnels[k.trailing_zeros() as usize];
+            flip_step_bit(self.init.s, &mut data, step, digit);
+            self.stats.neighbours += 1;
+
+            let (b1, b2) = self.pair(&data);
+            if self.message_filter(&b1)
+                && self.init.process_block(&b1) == self.init.process_block(&b2)
+            {
+                self.stats.collisions += 1;
+                return Some((b1, b2));
             }
         }
+        None
+    }
 
-        // Compute new m0 from adjusted a5
-        let m0 = op2_t(
-            a5,
-            3,
-            self.state.s[0],
-            self.state.s[1],
-            self.state.s[2],
-            self.state.s[3],
-        );
+    // The first message and the one with the path's differences
+    fn pair(&self, data: &U32Block) -> (U8Block, U8Block) {
+        let mut b1 = U8Block::default();
+        LE::write_u32_into(data, &mut b1);
+        let mut data2 = *data;
+        for &(i, delta) in &self.differences {
+            data2[i] = data2[i].wrapping_add(delta);
+        }
+        let mut b2 = U8Block::default();
+        LE::write_u32_into(&data2, &mut b2);
+        (b1, b2)
+    }
 
-        // Compute original a1..a2
-        let [a0, b0, c0, d0] = self.init.s;
-        let a1 = op1(a0, b0, c0, d0, self.data[0], 3);
-        let d1 = op1(d0, a1, b0, c0, self.data[1], 7);
-        let c1 = op1(c0, d1, a1, b0, self.data[2], 11);
-        let b1 = op1(b0, c1, d1, a1, self.data[3], 19);
-        let a2 = op1(a1, b1, c1, d1, self.data[4], 3);
-
-        // Compute new a1 from m0
-        let a1_ = op1(a0, b0, c0, d0, m0, 3);
-
-        // Update m0..m4
-        self.data[0] = m0;
-        self.data[1] = op1_t(d1, 7, d0, a1_, b0, c0);
-        self.data[2] = op1_t(c1, 11, c0, d1, a1_, b0);
-        self.data[3] = op1_t(b1, 19, b0, c1, d1, a1_);
-        self.data[4] = op1_t(a2, 3, a1_, b1, c1, d1);
-
-        // Write new a5
-        self.state.s[0] = a5;
-    }
-
-    fn second_round_d5(&mut self) {
-        // Compute d5
-        let mut d5 = op2(
-            self.state.s[3],
-            self.state.s[0],
-            self.state.s[1],
-            self.state.s[2],
-            self.data[4],
-            5,
-        );
+    /// Make `LANES` attempts at once, on the messages `find_once` would draw
+    /// in as many calls, and return the first collision among them. Round 1,
+    /// the a5, d5 corrections and the check of rounds 2 and 3 run on all
+    /// messages together in vector registers. Fixed bytes need words to be
+    /// redrawn one message at a time, so with them each attempt is scalar.
+    pub fn find_batch(&mut self) -> Option<(U8Block, U8Block)> {
+        if self.fixed_mask.iter().any(|&mask| mask != 0) {
+            return (0..LANES).find_map(|_| self.find_once());
+        }
+        lanes::dispatch(
+            #[inline(always)]
+            || self.find_lanes(),
+        )
+    }
 
-        // Adjust d5
-        for (digit, kind, pos) in CONSTRAINTS_D5.iter() {
-            match kind {
-                Equal => d5 ^= (d5 ^ self.state.s[*pos]) & (1u32 << digit),
-                Zero => d5 &= !(1u32 << digit),
-                One => d5 |= 1u32 << digit,
+    #[inline(always)]
+    fn find_lanes(&mut self) -> Option<(U8Block, U8Block)> {
+        // Draw the messages like init_message, one after the other
+        let mut data = [Lanes::default(); 16];
+        for l in 0..LANES {
+            self.init_message();
+            for (word, &m) in data.iter_mut().zip(&self.data) {
+                word.0[l] = m;
             }
         }
-        //        println!("{}", d5);
-
-        // Compute new m4 from adjusted d5
-        let m4 = op2_t(
-            d5,
-            5,
-            self.state.s[3],
-            self.state.s[0],
-            self.state.s[1],
-            self.state.s[2],
-        );
 
-        // Compute original a1..a3
-        let [a0, b0, c0, d0] = self.init.s;
-        let a1 = op1(a0, b0, c0, d0, self.data[0], 3);
-        let d1 = op1(d0, a1, b0, c0, self.data[1], 7);
-        let c1 = op1(c0, d1, a1, b0, self.data[2], 11);
-        let b1 = op1(b0, c1, d1, a1, self.data[3], 19);
-        let a2 = op1(a1, b1, c1, d1, self.data[4], 3);
-        let d2 = op1(d1, a2, b1, c1, self.data[5], 7);
-        let c2 = op1(c1, d2, a2, b1, self.data[6], 11);
-        let b2 = op1(b1, c2, d2, a2, self.data[7], 19);
-        let a3 = op1(a2, b2, c2, d2, self.data[8], 3);
-
-        // Compute new a2 from m4
-        let a2_ = op1(a1, b1, c1, d1, m4, 3);
-
-        // Update m4..m8
-        self.data[4] = m4;
-        self.data[5] = op1_t(d2, 7, d1, a2_, b1, c1);
-        self.data[6] = op1_t(c2, 11, c1, d2, a2_, b1);
-        self.data[7] = op1_t(b2, 19, b1, c2, d2, a2_);
-        self.data[8] = op1_t(a3, 3, a2_, b2, c2, d2);
-
-        // Write new d5
-        self.state.s[3] = d5;
-    }
-
-    fn init_message(&mut self) {
+        // First round
+        let init = self.init.s.map(Lanes::splat);
+        let mut q = init;
+        let shift = FIRST_ROUND_SHIFT;
+        let target_s = [0, 3, 2, 1];
+        for step in 0..16 {
+            let s = target_s[step % 4];
+            let [a, b, c, d] = [q[s], q[(s + 1) % 4], q[(s + 2) % 4], q[(s + 3) % 4]];
+            let v = adjust(
+                op1(a, b, c, d, data[step], shift[step % 4]),
+                &self.conditions[step],
+                b,
+                c,
+            );
+            data[step] = op1_t(v, shift[step % 4], a, b, c, d);
+            q[s] = v;
+        }
+
+        // Second round
+        correct_a5(init, &mut q, &mut data, &self.conditions[16]);
+        correct_d5(init, &mut q, &mut data, &self.conditions[17]);
+
+        // Filter the messages like message_pair, before counting them and
+        // their neighbours as find_once does; the tunnels leave m1 alone
+        let kept: [bool; LANES] =
+            std::array::from_fn(|l| !self.jpeg_mode || data[1].0[l] & 0x00FF_FFFF == 0x0001_FEFF);
+        let candidates = kept.iter().filter(|&&k| k).count() as u64;
+        self.stats.restarts += LANES as u64;
+        self.stats.candidates += candidates;
+        if candidates == 0 {
+            return None;
+        }
+
+        // Check rounds 2 and 3 of both messages, then of their neighbours
+        for k in 0..=self.neighbours() {
+            if k > 0 {
+                let (step, digit) = self.tunnels[k.trailing_zeros() as usize];
+                flip_step_bit(init, &mut data, step, digit);
+                self.stats.neighbours += candidates;
+            }
+
+            let mut data2 = data;
+            for &(i, delta) in &self.differences {
+                data2[i] = data2[i].wrapping_add(Lanes::splat(delta));
+            }
+            let (s1, s2) = (
+                self.init.process_lanes(&data),
+                self.init.process_lanes(&data2),
+            );
+
+            let found = (0..LANES).find(|&l| kept[l] && (0..4).all(|k| s1[k].0[l] == s2[k].0[l]));
+            if let Some(l) = found {
+                self.stats.collisions += 1;
+                let (mut b1, mut b2) = (U8Block::default(), U8Block::default());
+                LE::write_u32_into(&data.map(|w| w.0[l]), &mut b1);
+                LE::write_u32_into(&data2.map(|w| w.0[l]), &mut b2);
+                return Some((b1, b2));
+            }
+        }
+        None
+    }
+
+    /// Build a message pair following the path up to a5, d5, without
+    /// checking whether it collides
+    pub(crate) fn find_candidate(&mut self) -> Option<(U8Block, U8Block)> {
+        // Generate random message
+        self.init_message();
+
+        if !self.first_round() {
+            return None;
+        }
+        self.second_round();
+        self.message_pair()
+    }
+
+    // The stages of `find_candidate`, which the benchmarks also run alone
+
+    pub(crate) fn init_message(&mut self) {
         for i in &mut self.data {
-            *i = rand::random();
+            *i = self.rng.gen();
         }
         // specific generator for jpeg_mode
         if self.jpeg_mode {
             self.data[1] = 0x0001_FEFF + (self.data[1] & 0xFF00_0000);
         }
+        for i in 0..16 {
+            self.data[i] = (self.data[i] & !self.fixed_mask[i]) | self.fixed_value[i];
+        }
     }
 
-    fn message_filter(&self, b1: &U8Block, b2: &U8Block) -> bool {
-        self.init.process_block(&b1) == self.init.process_block(&b2)
-            // specific filter for jpeg_mode
-            && if self.jpeg_mode { self.data[1] & 0x00FF_FFFF == 0x0001_FEFF } else { true }
-    }
-
-    pub fn find_once(&mut self) -> Option<(U8Block, U8Block)> {
+    pub(crate) fn first_round(&mut self) -> bool {
         // Copy init state to state
         self.state = self.init;
 
-        // Generate random message
-        self.init_message();
-
-        // First round
         let shift = [3, 7, 11, 19];
         let target_s = [0, 3, 2, 1];
-        for i in 0..16 {
-            self.first_round_single_step(i, target_s[i % 4], shift[i % 4]);
-        }
+        (0..16).all(|i| self.first_round_single_step(i, target_s[i % 4], shift[i % 4]))
+    }
 
-        // Second round
-        self.second_round_a5();
-        self.second_round_d5();
+    pub(crate) fn second_round(&mut self) {
+        let init = self.init.s;
+        correct_a5(
+            init,
+            &mut self.state.s,
+            &mut self.data,
+            &self.conditions[16],
+        );
+        correct_d5(
+            init,
+            &mut self.state.s,
+            &mut self.data,
+            &self.conditions[17],
+        );
+    }
 
+    pub(crate) fn message_pair(&mut self) -> Option<(U8Block, U8Block)> {
         // Convert result into u8 array
         let mut b1 = U8Block::default();
         LE::write_u32_into(&self.data, &mut b1);
 
         // Create collision message
-        self.data[1] = self.data[1].wrapping_add(1 << 31);
-        self.data[2] = self.data[2].wrapping_add(1 << 31).wrapping_sub(1 << 28);
-        self.data[12] = self.data[12].wrapping_sub(1 << 16);
+        for &(i, delta) in &self.differences {
+            self.data[i] = self.data[i].wrapping_add(delta);
+        }
 
         let mut b2 = U8Block::default();
         LE::write_u32_into(&self.data, &mut b2);
 
-        if self.message_filter(&b1, &b2) {
+        if self.message_filter(&b1) {
             Some((b1, b2))
         } else {
             None
         }
     }
 }
+
+impl CollisionSearch for CollisionFinder {
+    type State = MD4State;
+    type Options = FinderOptions;
+
+    fn new(state: MD4State, options: &FinderOptions) -> CollisionFinder {
+        let mut finder = match &options.path {
+            Some(path) => CollisionFinder::with_path(state, path),
+            None => CollisionFinder::from(state),
+        };
+        finder.set_jpeg_mode(options.jpeg_mode);
+        if let Some(seed) = options.seed {
+            finder.set_seed(seed);
+        }
+        finder
+    }
+
+    fn find_messages(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (m1, m2) = self.find_batch()?;
+        Some((m1.to_vec(), m2.to_vec()))
+    }
+
+    // The state, then every option changing which collisions can be found.
+    // A path is identified by the MD4 digest of its text form.
+    fn cache_key(state: &MD4State, options: &FinderOptions) -> Option<String> {
+        let mut key = format!("md4-wang-{}", state_key(state));
+        if options.jpeg_mode {
+            key.push_str("-jpeg");
+        }
+        if let Some(path) = &options.path {
+            let digest = Md4::digest(path.to_text().as_bytes());
+            key.push_str(&format!("-path-{}", hex::encode(digest)));
+        }
+        if let Some(seed) = options.seed {
+            key.push_str(&format!("-seed-{}", seed));
+        }
+        Some(key)
+    }
+}
+
+/// The chaining state as a single hex string, for cache keys
+pub(crate) fn state_key(state: &MD4State) -> String {
+    state.s.iter().map(|w| format!("{:08x}", w)).collect()
+}
+
+#[cfg(test)]
+mod collision_tests {
+    use crate::collision::*;
+
+    #[test]
+    fn published_pair_collides() {
+        let (b1, b2) = wang_pair();
+        assert_ne!(b1, b2);
+        let init = MD4State::new();
+        assert_eq!(init.process_block(&b1), init.process_block(&b2));
+    }
+
+    #[test]
+    fn batch_finds_scalar_collision() {
+        let init = MD4State::new();
+        let mut scalar = CollisionFinder::from(init);
+        scalar.set_seed(3);
+        scalar.set_tunnel_depth(0);
+        let mut attempts = 0usize;
+        let pair = loop {
+            attempts += 1;
+            if let Some(pair) = scalar.find_once() {
+                break pair;
+            }
+        };
+
+        // Without tunnels, the batches try the same messages in the same order
+        let mut batched = CollisionFinder::from(init);
+        batched.set_seed(3);
+        batched.set_tunnel_depth(0);
+        for _ in 1..attempts.div_ceil(LANES) {
+            assert_eq!(batched.find_batch(), None);
+        }
+        assert_eq!(batched.find_batch(), Some(pair));
+    }
+
+    #[test]
+    fn tunnels_keep_round_one() {
+        let init = MD4State::new();
+        let mut finder = CollisionFinder::from(init);
+        finder.set_seed(9);
+        finder.init_message();
+        assert!(finder.first_round());
+        finder.second_round();
+        let data = finder.data;
+
+        for &(step, digit) in &finder.tunnels {
+            let mut flipped = data;
+            flip_step_bit(init.s, &mut flipped, step, digit);
+            assert_eq!(flipped[..5], data[..5]);
+
+            let (before, after) = (init.step_values(&data), init.step_values(&flipped));
+            for i in 0..16 {
+                let expected = if i == step { 1 << digit } else { 0 };
+                assert_eq!(before[i] ^ after[i], expected);
+            }
+            assert_eq!(before[16..18], after[16..18]);
+        }
+    }
+
+    #[test]
+    fn tunnels_find_collisions() {
+        let init = MD4State::new();
+        let mut finder = CollisionFinder::from(init);
+        finder.set_seed(3);
+        finder.set_tunnel_depth(8);
+        assert!(finder.tunnel_count() > 100);
+
+        let (b1, b2) = loop {
+            if let Some(pair) = finder.find_once() {
+                break pair;
+            }
+        };
+        assert_eq!(init.process_block(&b1), init.process_block(&b2));
+
+        let stats = finder.stats();
+        assert_eq!(stats.collisions, 1);
+        assert!(stats.neighbours > stats.restarts);
+    }
+}
@@ -0,0 +1,36 @@
+use crate::builder::CancelToken;
+use crate::common::*;
+use block_buffer::generic_array::ArrayLength;
+
+/// Chaining state of an iterated compression function, which `Builder` feeds
+/// with the prefix of the collision
+pub trait CompressionState: Copy + Default + PartialEq {
+    /// Size of the blocks the compression function takes, in bytes
+    type BlockSize: ArrayLength<u8>;
+
+    fn apply_block(&mut self, input: &GenericArray<u8, Self::BlockSize>);
+}
+
+/// A randomized search for two messages colliding from a given chaining
+/// state. Each message is a whole number of blocks, possibly more than one.
+pub trait CollisionSearch {
+    type State: CompressionState;
+    /// Settings passed on by `Builder` when it creates the search
+    type Options: Default;
+
+    fn new(state: Self::State, options: &Self::Options) -> Self;
+
+    /// Make one attempt, returning both messages on success
+    fn find_messages(&mut self) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Give up the attempt in progress once `token` is cancelled. `Builder`
+    /// checks the token between attempts, so only searches with long
+    /// attempts need it; the default ignores it.
+    fn set_cancel_token(&mut self, _token: CancelToken) {}
+
+    /// Name of the collisions found from `state` with `options`, under which
+    /// `Builder` caches them. `None`, the default, disables the cache.
+    fn cache_key(_state: &Self::State, _options: &Self::Options) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,132 @@
+use crate::collision::CollisionFinder;
+use crate::common::*;
+use crate::path::DifferentialPath;
+use crate::state::MD4State;
+use crate::trace::trace;
+use byteorder::{ByteOrder, LE};
+
+// A block conforms to a differential path when it meets every sufficient
+// condition of the path, so that adding the path's message differences gives
+// a second preimage. A random block conforms to the Wang path with
+// probability about 2^-122, so conforming blocks are generated with the
+// collision search rather than found. These are not the weak messages of Yu,
+// Wang, Yun and Wang, which follow a dedicated path with a much smaller
+// condition set; that path is not implemented.
+
+/// Result of checking a block against a differential path
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConformingCheck {
+    /// Number of sufficient conditions of the path the block violates
+    pub violations: usize,
+    /// The block with the path's message differences applied, if it has the
+    /// same compression output as the original block
+    pub second_preimage: Option<U8Block>,
+}
+
+impl ConformingCheck {
+    pub fn conforms(&self) -> bool {
+        self.second_preimage.is_some()
+    }
+}
+
+/// Apply message word differences to a block
+pub fn apply_differences(block: &U8Block, differences: &[(usize, u32)]) -> U8Block {
+    let mut words = U32Block::default();
+    LE::read_u32_into(block, &mut words);
+    for &(i, delta) in differences {
+        words[i] = words[i].wrapping_add(delta);
+    }
+
+    let mut partner = U8Block::default();
+    LE::write_u32_into(&words, &mut partner);
+    partner
+}
+
+/// Check whether a block conforms to `path` from chaining state `init`, i.e.
+/// whether it has a second preimage differing by the path's message
+/// differences
+pub fn check_conforming(
+    init: &MD4State,
+    block: &U8Block,
+    path: &DifferentialPath,
+) -> ConformingCheck {
+    let violations = trace(init, block, path).violations();
+    let partner = apply_differences(block, &path.message_differences);
+    let second = partner != *block && init.process_block(block) == init.process_block(&partner);
+
+    ConformingCheck {
+        violations,
+        second_preimage: if second { Some(partner) } else { None },
+    }
+}
+
+// Restarts without a single message keeping the fixed bytes, after which
+// they are taken to conflict with the path's conditions
+const PROBE_RESTARTS: u64 = 1 << 16;
+
+// Enough for a few fixed bytes, and some 15 seconds of a release build
+const MAX_RESTARTS: u64 = 1 << 25;
+
+/// Generate a message conforming to `path` and its second preimage, with the
+/// bytes at the given offsets of the message set to the given values
+pub fn conforming_message(
+    init: MD4State,
+    path: &DifferentialPath,
+    fixed: &[(usize, u8)],
+) -> Result<(U8Block, U8Block), &'static str> {
+    let mut finder = CollisionFinder::with_path(init, path);
+    finder.set_fixed_bytes(fixed)?;
+    loop {
+        if let Some(pair) = finder.find_once() {
+            return Ok(pair);
+        }
+
+        let stats = finder.stats();
+        if stats.candidates == 0 && stats.restarts >= PROBE_RESTARTS {
+            return Err("fixed bytes conflict with the path's conditions");
+        }
+        if stats.restarts >= MAX_RESTARTS {
+            return Err("no conforming message found with these fixed bytes");
+        }
+    }
+}
+
+#[cfg(test)]
+mod conforming_tests {
+    use crate::conforming::*;
+
+    #[test]
+    fn generated_messages_conform() {
+        let init = MD4State::new();
+        let path = DifferentialPath::wang();
+        let (m1, m2) = conforming_message(init, &path, &[(40, b'M')]).unwrap();
+        assert_eq!(m1[40], b'M');
+
+        let check = check_conforming(&init, &m1, &path);
+        assert_eq!(check.violations, 0);
+        assert_eq!(check.second_preimage, Some(m2));
+    }
+
+    #[test]
+    fn conflicting_bytes_are_rejected() {
+        let init = MD4State::new();
+        let path = DifferentialPath::wang();
+        assert!(conforming_message(init, &path, &[(64, 0)]).is_err());
+
+        // A whole fixed block cannot follow round 1
+        let fixed: Vec<_> = (0..64).map(|i| (i, 0)).collect();
+        assert_eq!(
+            conforming_message(init, &path, &fixed),
+            Err("fixed bytes conflict with the path's conditions")
+        );
+    }
+
+    #[test]
+    fn random_message_does_not_conform() {
+        let init = MD4State::new();
+        let block = U8Block::clone_from_slice(&[0x5a; 64]);
+        let check = check_conforming(&init, &block, &DifferentialPath::wang());
+        assert!(!check.conforms());
+        assert!(check.violations > 0);
+    }
+}
@@ -0,0 +1,430 @@
+use crate::builder::CancelToken;
+use crate::collision::{state_key, CollisionFinder};
+use crate::common::*;
+use crate::compression::CollisionSearch;
+use crate::ops::{f, g, op1, op1_t, op2_t};
+use crate::state::MD4State;
+use byteorder::{ByteOrder, LE};
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+/// Message word difference (M' - M) of Dobbertin's attack
+pub const DOBBERTIN_DIFFERENCE: (usize, u32) = (12, 1);
+
+// Step values of the inner almost-collision that do not depend on the random
+// choices: with a4 = -1 in the first message and 0 in the second, the
+// difference in m12 is absorbed by a carry through every bit. The first
+// message's c4 and b4 were found by a search over sparse words, together
+// with the difference between b3 and c3.
+const A4: u32 = 0xffff_ffff;
+const D4: u32 = 0x0001_fff8;
+const C4: u32 = 0xfdff_bfff;
+const C3_MINUS_B3: u32 = 0xffff_ffc0;
+
+// After the inner almost-collision, c5 goes down by 2^5 and b5 by 2^25
+const C5_DELTA: u32 = 0u32.wrapping_sub(1 << 5);
+const B5_DELTA: u32 = 0u32.wrapping_sub(1 << 25);
+
+// Connections tried before a new inner almost-collision is generated
+const CONNECTIONS: u64 = 1 << 24;
+
+// Contributions to G(y, z, q) - G(y', z', q) of each bit of q, when it is 0
+// and when it is 1
+fn majority_options(y: u32, y2: u32, z: u32, z2: u32) -> [(i64, i64); 32] {
+    let mut options = [(0, 0); 32];
+    for (i, o) in options.iter_mut().enumerate() {
+        let bit = |q: u32| {
+            i64::from((g(q << i, y2, z2) >> i) & 1) - i64::from((g(q << i, y, z) >> i) & 1)
+        };
+        *o = (bit(0), bit(1));
+    }
+    options
+}
+
+// Choose the bits of a word from i on so that their contributions sum to the
+// remainder r modulo 2^32, carry by carry
+fn solve_bits(options: &[(i64, i64); 32], i: usize, r: u64, bits: u32) -> Option<u32> {
+    if i == 32 {
+        return if r & 0xffff_ffff == 0 {
+            Some(bits)
+        } else {
+            None
+        };
+    }
+    let (o0, o1) = options[i];
+    for (b, o) in [(0, o0), (1, o1)] {
+        if (o & 1) as u64 == (r >> i) & 1 {
+            let rest = r.wrapping_sub((o << i) as u64);
+            if let Some(bits) = solve_bits(options, i + 1, rest, bits | (b << i)) {
+                return Some(bits);
+            }
+        }
+        if o0 == o1 {
+            break;
+        }
+    }
+    None
+}
+
+// A random word whose bits are those of `solution` where the options differ
+fn fill(options: &[(i64, i64); 32], solution: u32, rng: &mut StdRng) -> u32 {
+    let fixed = (0..32)
+        .filter(|&i| options[i].0 != options[i].1)
+        .fold(0, |m, i| m | (1u32 << i));
+    (solution & fixed) | (rng.gen::<u32>() & !fixed)
+}
+
+// The words of the first message fixed by an inner almost-collision (m0, m4,
+// m8 and m12 to m15), and the step values a3, d3, c3, b3 it starts from
+struct Inner {
+    data: U32Block,
+    q: [u32; 4],
+}
+
+impl Inner {
+    // Find step values for which the m12 difference vanishes after a5 and d5,
+    // leaving only the differences in c5 and b5 that rounds 2 and 3 cancel;
+    // None if `cancel` is cancelled first
+    fn find(cancel: &CancelToken, rng: &mut StdRng) -> Option<Inner> {
+        while !cancel.is_cancelled() {
+            if let Some(inner) = Inner::find_once(rng) {
+                return Some(inner);
+            }
+        }
+        None
+    }
+
+    fn find_once(rng: &mut StdRng) -> Option<Inner> {
+        let (a4, a4_2) = (A4, 0);
+        let b3: u32 = rng.gen();
+        let c3 = b3.wrapping_add(C3_MINUS_B3);
+        let b4: u32 = rng.gen();
+
+        // Values of the second message, where the inputs of F differ
+        let d4_2 = D4
+            .rotate_right(7)
+            .wrapping_add(f(a4_2, b3, c3))
+            .wrapping_sub(f(a4, b3, c3))
+            .rotate_left(7);
+        let c4_2 = C4
+            .rotate_right(11)
+            .wrapping_add(f(d4_2, a4_2, b3))
+            .wrapping_sub(f(D4, a4, b3))
+            .rotate_left(11);
+        let b4_2 = b4
+            .rotate_right(19)
+            .wrapping_add(f(c4_2, d4_2, a4_2))
+            .wrapping_sub(f(C4, D4, a4))
+            .rotate_left(19);
+
+        // a5: the difference of a4 cancels that of G
+        if 1u32
+            .wrapping_add(g(b4_2, c4_2, d4_2))
+            .wrapping_sub(g(b4, C4, D4))
+            != 0
+        {
+            return None;
+        }
+
+        // d5: the bits of a5 cancel the difference of d4
+        let options = majority_options(b4, b4_2, C4, c4_2);
+        let solution = solve_bits(&options, 0, u64::from(D4.wrapping_sub(d4_2)), 0)?;
+        let a5 = fill(&options, solution, rng);
+
+        // c5: the bits of d5 leave a difference of -2^28 before the rotation,
+        // which takes bit 5 of c5 down
+        let options = majority_options(a5, a5, b4, b4_2);
+        let target = 0u32
+            .wrapping_sub(1 << 28)
+            .wrapping_sub(c4_2.wrapping_sub(C4));
+        let solution = solve_bits(&options, 0, u64::from(target), 0)?;
+        let d5 = fill(&options, solution, rng);
+        let c5: u32 = rng.gen::<u32>() | (1 << 5);
+        let b5: u32 = rng.gen::<u32>() | (1 << 25);
+
+        let d5_2 = d5
+            .rotate_right(5)
+            .wrapping_add(d4_2.wrapping_sub(D4))
+            .wrapping_add(g(a5, b4_2, c4_2))
+            .wrapping_sub(g(a5, b4, C4))
+            .rotate_left(5);
+        let c5_2 = c5
+            .rotate_right(9)
+            .wrapping_add(c4_2.wrapping_sub(C4))
+            .wrapping_add(g(d5, a5, b4_2))
+            .wrapping_sub(g(d5, a5, b4))
+            .rotate_left(9);
+        let b5_2 = b5
+            .rotate_right(13)
+            .wrapping_add(b4_2.wrapping_sub(b4))
+            .wrapping_add(g(c5_2, d5, a5))
+            .wrapping_sub(g(c5, d5, a5))
+            .wrapping_add(1)
+            .rotate_left(13);
+        if d5_2 != d5 || c5_2 != c5.wrapping_add(C5_DELTA) || b5_2 != b5.wrapping_add(B5_DELTA) {
+            return None;
+        }
+
+        // a6 must not see the differences of c5 and b5, whatever m1 is
+        if g(b5, c5, d5) != g(b5_2, c5_2, d5) {
+            return None;
+        }
+
+        // Solve the fixed message words from the step values
+        let d3: u32 = rng.gen();
+        let mut data = U32Block::default();
+        data[13] = op1_t(D4, 7, d3, a4, b3, c3);
+        data[14] = op1_t(C4, 11, c3, D4, a4, b3);
+        data[15] = op1_t(b4, 19, b3, C4, D4, a4);
+        data[0] = op2_t(a5, 3, a4, b4, C4, D4);
+        data[4] = op2_t(d5, 5, D4, a5, b4, C4);
+        data[8] = op2_t(c5, 9, C4, d5, a5, b4);
+        data[12] = op2_t(b5, 13, b4, c5, d5, a5);
+
+        // a4 = -1 leaves a3 as the only unknown of step 12
+        let a3 = op1_t(a4, 3, 0, b3, c3, d3).wrapping_sub(data[12]);
+        Some(Inner {
+            data,
+            q: [a3, d3, c3, b3],
+        })
+    }
+}
+
+/// Search for a pair of blocks colliding under MD4 from a given chaining state,
+/// following Dobbertin's 1996 attack: the messages differ by 1 in m12 only.
+///
+/// An inner almost-collision makes that difference vanish over steps 12 to 19
+/// except for one bit in each of c5 and b5, which rounds 2 and 3 cancel with
+/// probability about 2^-21. Each attempt connects the inner almost-collision
+/// to the chaining state through the first eight steps and checks the result.
+pub struct DobbertinCollisionFinder {
+    init: MD4State,
+    inner: Option<Inner>,
+    connections: u64,
+    cancel: CancelToken,
+    rng: StdRng,
+}
+
+impl DobbertinCollisionFinder {
+    pub fn from(state: MD4State) -> DobbertinCollisionFinder {
+        DobbertinCollisionFinder {
+            init: state,
+            inner: None,
+            connections: 0,
+            cancel: CancelToken::new(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Draw the random step values from a generator seeded with `seed`, so
+    /// that the same seed finds the same collision
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn find_once(&mut self) -> Option<(U8Block, U8Block)> {
+        let (b1, b2) = self.connect()?;
+        if self.init.process_block(&b1) == self.init.process_block(&b2) {
+            Some((b1, b2))
+        } else {
+            None
+        }
+    }
+
+    // Connect an inner almost-collision to the chaining state, so that the
+    // pair differs in steps 12 to 19 only, without checking later steps. None
+    // if the search for a new inner almost-collision is cancelled.
+    fn connect(&mut self) -> Option<(U8Block, U8Block)> {
+        if self.connections.is_multiple_of(CONNECTIONS) {
+            self.inner = None;
+        }
+        if self.inner.is_none() {
+            self.inner = Some(Inner::find(&self.cancel, &mut self.rng)?);
+        }
+        self.connections += 1;
+        let inner = self.inner.as_ref()?;
+
+        // q[i + 4] is the value written by step i
+        let s = self.init.s;
+        let mut q = [0u32; 16];
+        q[..4].copy_from_slice(&[s[0], s[3], s[2], s[1]]);
+        q[12..].copy_from_slice(&inner.q);
+        let mut data = inner.data;
+        let shift = [3, 7, 11, 19];
+        let step = |q: &[u32; 16], i: usize, m: u32| {
+            op1(q[i], q[i + 3], q[i + 2], q[i + 1], m, shift[i % 4])
+        };
+        let solve = |q: &[u32; 16], i: usize, v: u32| {
+            op1_t(v, shift[i % 4], q[i], q[i + 3], q[i + 2], q[i + 1])
+        };
+
+        // Steps 0 and 4 have their words fixed, steps 1 to 3 are random
+        q[4] = step(&q, 0, data[0]);
+        for i in 1..4 {
+            q[i + 4] = self.rng.gen();
+            data[i] = solve(&q, i, q[i + 4]);
+        }
+        q[8] = step(&q, 4, data[4]);
+
+        // Step 8 reaches a3 with the fixed m8 if F(b2, c2, d2) takes the
+        // right value: d2 decides the bits where c2 cannot
+        let t = inner.q[0]
+            .rotate_right(3)
+            .wrapping_sub(q[8])
+            .wrapping_sub(data[8]);
+        let c2: u32 = self.rng.gen();
+        let d2 = (t & (c2 ^ t)) | (self.rng.gen::<u32>() & !(c2 ^ t));
+        let b2 = !(c2 ^ t) & ((d2 ^ t) | self.rng.gen::<u32>());
+        q[9] = d2;
+        q[10] = c2;
+        q[11] = b2;
+        for i in 5..8 {
+            data[i] = solve(&q, i, q[i + 4]);
+        }
+        for i in 9..12 {
+            data[i] = solve(&q, i, q[i + 4]);
+        }
+
+        let mut b1 = U8Block::default();
+        LE::write_u32_into(&data, &mut b1);
+        let (word, delta) = DOBBERTIN_DIFFERENCE;
+        data[word] = data[word].wrapping_add(delta);
+        let mut b2 = U8Block::default();
+        LE::write_u32_into(&data, &mut b2);
+        Some((b1, b2))
+    }
+}
+
+impl CollisionSearch for DobbertinCollisionFinder {
+    type State = MD4State;
+    type Options = ();
+
+    fn new(state: MD4State, _: &()) -> DobbertinCollisionFinder {
+        DobbertinCollisionFinder::from(state)
+    }
+
+    fn find_messages(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (m1, m2) = self.find_once()?;
+        Some((m1.to_vec(), m2.to_vec()))
+    }
+
+    fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    fn cache_key(state: &MD4State, _: &()) -> Option<String> {
+        Some(format!("md4-dobbertin-{}", state_key(state)))
+    }
+}
+
+/// Time taken by an MD4 collision attack to find a number of collisions
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub algorithm: &'static str,
+    pub collisions: usize,
+    /// Attempts made, successful or not
+    pub attempts: u64,
+    pub elapsed: Duration,
+}
+
+fn time_search(
+    algorithm: &'static str,
+    collisions: usize,
+    mut find_once: impl FnMut() -> Option<(U8Block, U8Block)>,
+) -> Timing {
+    let start = Instant::now();
+    let mut attempts = 0;
+    for _ in 0..collisions {
+        loop {
+            attempts += 1;
+            if find_once().is_some() {
+                break;
+            }
+        }
+    }
+    Timing {
+        algorithm,
+        collisions,
+        attempts,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// Find `collisions` collisions from the MD4 IV with the Wang attack, then
+/// with Dobbertin's, both seeded with `seed` so that the attempts repeat
+pub fn compare_algorithms(collisions: usize, seed: u64) -> [Timing; 2] {
+    let mut wang = CollisionFinder::from(MD4State::new());
+    wang.set_seed(seed);
+    let mut dobbertin = DobbertinCollisionFinder::from(MD4State::new());
+    dobbertin.set_seed(seed);
+    [
+        time_search("wang", collisions, || wang.find_once()),
+        time_search("dobbertin", collisions, || dobbertin.find_once()),
+    ]
+}
+
+#[cfg(test)]
+mod dobbertin_tests {
+    use crate::dobbertin::*;
+
+    #[test]
+    fn inner_almost_collision() {
+        let init = MD4State::new();
+        let mut finder = DobbertinCollisionFinder::from(init);
+        let (b1, b2) = finder.connect().unwrap();
+        let mut data = U32Block::default();
+        LE::read_u32_into(&b1, &mut data);
+        let v1 = init.step_values(&data);
+        LE::read_u32_into(&b2, &mut data);
+        let v2 = init.step_values(&data);
+
+        let differ: Vec<usize> = (0..20).filter(|&i| v1[i] != v2[i]).collect();
+        assert_eq!(differ, [12, 13, 14, 15, 18, 19]);
+        assert_eq!(v2[18].wrapping_sub(v1[18]), C5_DELTA);
+        assert_eq!(v2[19].wrapping_sub(v1[19]), B5_DELTA);
+    }
+
+    #[test]
+    fn find_collision() {
+        // Seed 19 needs some 700k attempts, a few seconds in a debug build
+        let init = MD4State::new();
+        let mut finder = DobbertinCollisionFinder::from(init);
+        finder.set_seed(19);
+        let (b1, b2) = loop {
+            if let Some(pair) = finder.find_once() {
+                break pair;
+            }
+        };
+        assert_ne!(b1, b2);
+        assert_eq!(init.process_block(&b1), init.process_block(&b2));
+    }
+
+    #[test]
+    fn dobbertin_collision() {
+        // Found by DobbertinCollisionFinder from the MD4 IV
+        let mut data: U32Block = [
+            0xf942_0182,
+            0xe741_a288,
+            0x15cf_c7c4,
+            0x1178_040f,
+            0x00f7_b170,
+            0x1f4e_396c,
+            0xaebe_5ab4,
+            0x8367_a221,
+            0xc0a5_768a,
+            0x1279_bc86,
+            0xdc68_c81b,
+            0x1a6e_99ca,
+            0xddbd_18ff,
+            0x2e60_97f2,
+            0xb5d4_36d3,
+            0xa289_5b69,
+        ]
+        .into();
+        let init = MD4State::new();
+        let h1 = init.process_u32array(&data);
+        data[12] = data[12].wrapping_add(1);
+        assert_eq!(h1, init.process_u32array(&data));
+    }
+}
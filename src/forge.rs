@@ -0,0 +1,109 @@
+use crate::common::*;
+use crate::state::MD4State;
+use byteorder::{ByteOrder, LE};
+use std::ops::RangeInclusive;
+
+/// A forged digest for one guess of the original message length
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forgery {
+    pub original_len: u64,
+    pub glue: Vec<u8>,
+    pub digest: [u8; 16],
+}
+
+/// Compute the MD4 padding appended to a message of `len` bytes
+pub fn glue_padding(len: u64) -> Vec<u8> {
+    let mut glue = vec![0x80u8];
+    let zeros = (119 - (len % 64) as usize) % 64;
+    glue.resize(1 + zeros + 8, 0);
+
+    let tail = glue.len() - 8;
+    LE::write_u64(&mut glue[tail..], len.wrapping_mul(8));
+    glue
+}
+
+/// Continue hashing `data` from `state`, which has already absorbed `processed`
+/// bytes (a multiple of 64), and return the final digest
+pub fn extend(state: MD4State, processed: u64, data: &[u8]) -> [u8; 16] {
+    let total = processed.wrapping_add(data.len() as u64);
+    let mut message = data.to_vec();
+    message.extend_from_slice(&glue_padding(total));
+
+    let mut state = state;
+    for block in message.chunks(64) {
+        state.apply_block(U8Block::from_slice(block));
+    }
+    state.digest()
+}
+
+/// Forge the digest of `original || glue || ext` given only the digest and the
+/// length of `original`
+pub fn forge(digest: &[u8; 16], original_len: u64, ext: &[u8]) -> Forgery {
+    let glue = glue_padding(original_len);
+    let processed = original_len.wrapping_add(glue.len() as u64);
+    let state = MD4State::from_digest(digest);
+
+    Forgery {
+        original_len,
+        digest: extend(state, processed, ext),
+        glue,
+    }
+}
+
+/// Forge one digest per candidate secret length when only the length of the
+/// public part of `secret || msg` is known
+pub fn forge_range(
+    digest: &[u8; 16],
+    msg_len: u64,
+    secret_lens: RangeInclusive<u64>,
+    ext: &[u8],
+) -> Vec<Forgery> {
+    secret_lens
+        .map(|secret_len| forge(digest, secret_len + msg_len, ext))
+        .collect()
+}
+
+#[cfg(test)]
+mod forge_tests {
+    use crate::forge::*;
+    use md4::{Digest, Md4};
+
+    fn md4(parts: &[&[u8]]) -> [u8; 16] {
+        let mut hasher = Md4::new();
+        for part in parts {
+            hasher.input(part);
+        }
+        let mut digest = [0u8; 16];
+        digest.copy_from_slice(&hasher.result());
+        digest
+    }
+
+    #[test]
+    fn forge_matches_real_hash() {
+        for secret_len in &[0usize, 1, 16, 55, 56, 63, 64, 100] {
+            let secret = vec![rand::random::<u8>(); *secret_len];
+            let msg = b"user=guest&role=reader";
+            let ext = b"&role=admin";
+
+            let digest = md4(&[&secret, msg]);
+            let forgery = forge(&digest, (secret.len() + msg.len()) as u64, ext);
+
+            assert_eq!(forgery.digest, md4(&[&secret, msg, &forgery.glue, ext]));
+        }
+    }
+
+    #[test]
+    fn forge_over_secret_lengths() {
+        let secret = b"0123456789abcdef0123";
+        let msg = b"count=10";
+        let ext = b";count=1000000";
+
+        let digest = md4(&[secret, msg]);
+        let forgeries = forge_range(&digest, msg.len() as u64, 10..=30, ext);
+        assert_eq!(forgeries.len(), 21);
+
+        let hit = &forgeries[secret.len() - 10];
+        assert_eq!(hit.original_len, (secret.len() + msg.len()) as u64);
+        assert_eq!(hit.digest, md4(&[secret, msg, &hit.glue, ext]));
+    }
+}
@@ -0,0 +1,193 @@
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+
+/// Number of candidate messages a `CollisionFinder` evaluates together
+pub const LANES: usize = 8;
+
+/// A 32-bit word, or one word of each of `LANES` independent computations,
+/// so that the step functions of `ops` serve both
+pub trait Word:
+    Copy + BitAnd<Output = Self> + BitOr<Output = Self> + BitXor<Output = Self> + Not<Output = Self>
+{
+    fn splat(x: u32) -> Self;
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+    fn rotate_left(self, s: u32) -> Self;
+    fn rotate_right(self, s: u32) -> Self;
+}
+
+impl Word for u32 {
+    #[inline(always)]
+    fn splat(x: u32) -> u32 {
+        x
+    }
+
+    #[inline(always)]
+    fn wrapping_add(self, other: u32) -> u32 {
+        u32::wrapping_add(self, other)
+    }
+
+    #[inline(always)]
+    fn wrapping_sub(self, other: u32) -> u32 {
+        u32::wrapping_sub(self, other)
+    }
+
+    #[inline(always)]
+    fn rotate_left(self, s: u32) -> u32 {
+        u32::rotate_left(self, s)
+    }
+
+    #[inline(always)]
+    fn rotate_right(self, s: u32) -> u32 {
+        u32::rotate_right(self, s)
+    }
+}
+
+/// One word per lane. Every operation is the same on all lanes, which the
+/// compiler turns into SSE2 or AVX2 instructions, or plain loops elsewhere.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[repr(align(32))]
+pub struct Lanes(pub [u32; LANES]);
+
+impl Lanes {
+    #[inline(always)]
+    fn map(self, op: impl Fn(u32) -> u32) -> Lanes {
+        let mut out = self;
+        for x in &mut out.0 {
+            *x = op(*x);
+        }
+        out
+    }
+
+    #[inline(always)]
+    fn zip(self, other: Lanes, op: impl Fn(u32, u32) -> u32) -> Lanes {
+        let mut out = self;
+        for (x, y) in out.0.iter_mut().zip(&other.0) {
+            *x = op(*x, *y);
+        }
+        out
+    }
+}
+
+impl BitAnd for Lanes {
+    type Output = Lanes;
+
+    #[inline(always)]
+    fn bitand(self, other: Lanes) -> Lanes {
+        self.zip(other, |x, y| x & y)
+    }
+}
+
+impl BitOr for Lanes {
+    type Output = Lanes;
+
+    #[inline(always)]
+    fn bitor(self, other: Lanes) -> Lanes {
+        self.zip(other, |x, y| x | y)
+    }
+}
+
+impl BitXor for Lanes {
+    type Output = Lanes;
+
+    #[inline(always)]
+    fn bitxor(self, other: Lanes) -> Lanes {
+        self.zip(other, |x, y| x ^ y)
+    }
+}
+
+impl Not for Lanes {
+    type Output = Lanes;
+
+    #[inline(always)]
+    fn not(self) -> Lanes {
+        self.map(|x| !x)
+    }
+}
+
+impl Word for Lanes {
+    #[inline(always)]
+    fn splat(x: u32) -> Lanes {
+        Lanes([x; LANES])
+    }
+
+    #[inline(always)]
+    fn wrapping_add(self, other: Lanes) -> Lanes {
+        self.zip(other, u32::wrapping_add)
+    }
+
+    #[inline(always)]
+    fn wrapping_sub(self, other: Lanes) -> Lanes {
+        self.zip(other, u32::wrapping_sub)
+    }
+
+    #[inline(always)]
+    fn rotate_left(self, s: u32) -> Lanes {
+        self.map(|x| x.rotate_left(s))
+    }
+
+    #[inline(always)]
+    fn rotate_right(self, s: u32) -> Lanes {
+        self.map(|x| x.rotate_right(s))
+    }
+}
+
+/// Run `search` compiled for AVX-512 or AVX2 when the CPU has them, and for
+/// the baseline target (SSE2 on x86_64) otherwise. `search` must be inlined
+/// here for the choice to matter, so callers mark the closure and the lane
+/// code it runs `#[inline(always)]`.
+#[inline(always)]
+pub(crate) fn dispatch<T>(search: impl FnOnce() -> T) -> T {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // AVX-512 adds rotations, which take three instructions with AVX2
+        #[target_feature(enable = "avx512f,avx512vl")]
+        fn avx512<T>(search: impl FnOnce() -> T) -> T {
+            search()
+        }
+
+        #[target_feature(enable = "avx2")]
+        fn avx2<T>(search: impl FnOnce() -> T) -> T {
+            search()
+        }
+
+        if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vl") {
+            // SAFETY: the CPU supports AVX-512F and VL, checked just above
+            return unsafe { avx512(search) };
+        }
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: the CPU supports AVX2, checked just above
+            return unsafe { avx2(search) };
+        }
+    }
+    search()
+}
+
+#[cfg(test)]
+mod lanes_tests {
+    use crate::lanes::*;
+    use crate::ops::{op1, op2_t, op3};
+
+    #[test]
+    fn lanes_match_words() {
+        let word = |l: usize, i: u32| (l as u32 + 1).wrapping_mul(0x9E37_79B9).rotate_left(i);
+        let lanes = |i: u32| {
+            let mut x = Lanes::default();
+            for l in 0..LANES {
+                x.0[l] = word(l, i);
+            }
+            x
+        };
+        let (a, b, c, d, m) = (lanes(0), lanes(1), lanes(2), lanes(3), lanes(4));
+        let (v1, v2, v3) = (
+            op1(a, b, c, d, m, 7),
+            op2_t(a, 5, b, c, d, m),
+            op3(a, b, c, d, m, 11),
+        );
+        for l in 0..LANES {
+            let w = |i| word(l, i);
+            assert_eq!(v1.0[l], op1(w(0), w(1), w(2), w(3), w(4), 7));
+            assert_eq!(v2.0[l], op2_t(w(0), 5, w(1), w(2), w(3), w(4)));
+            assert_eq!(v3.0[l], op3(w(0), w(1), w(2), w(3), w(4), 11));
+        }
+    }
+}
@@ -1,9 +1,60 @@
 mod app;
+mod append;
+mod batch;
+mod bench;
 mod builder;
+mod cache;
 mod collision;
 mod common;
+mod compression;
+mod conforming;
+mod dobbertin;
+mod forge;
+mod lanes;
+mod md5_collision;
+mod md5_state;
+mod multi;
+mod offset;
 mod ops;
+mod path;
+mod reduced;
+mod ripemd;
+mod rsync;
+mod search;
 mod state;
+mod trace;
+mod verify;
 
 pub use crate::app::{App, Opt};
-pub use crate::builder::Builder;
+pub use crate::append::{append_common_suffix, shares_state};
+pub use crate::batch::{batch, BatchResult};
+pub use crate::bench::{bench, BenchConfig, BenchReport, Measurement, BENCH_FORMAT_VERSION};
+pub use crate::builder::{Builder, CancelToken, DobbertinBuilder, MD5Builder, Progress, Search};
+pub use crate::cache::{CacheEntry, CollisionCache};
+pub use crate::collision::{
+    CollisionFinder, FinderOptions, SearchStats, DEFAULT_TUNNEL_DEPTH, WANG_DIFFERENTIAL,
+};
+pub use crate::common::U8Block;
+pub use crate::compression::{CollisionSearch, CompressionState};
+pub use crate::conforming::{
+    apply_differences, check_conforming, conforming_message, ConformingCheck,
+};
+pub use crate::dobbertin::{
+    compare_algorithms, DobbertinCollisionFinder, Timing, DOBBERTIN_DIFFERENCE,
+};
+pub use crate::forge::{extend, forge, forge_range, glue_padding, Forgery};
+pub use crate::lanes::{Lanes, Word, LANES};
+pub use crate::md5_collision::{MD5CollisionFinder, WANG_MD5_PAIR};
+pub use crate::md5_state::MD5State;
+pub use crate::multi::{combine, multi_collide, CollisionPoint};
+pub use crate::offset::Offset;
+pub use crate::path::{BitCondition, DifferentialPath};
+pub use crate::reduced::{
+    lab, reduced_preimage, LabResult, ReducedCollisionFinder, ReducedMD4State, Shortcut,
+};
+pub use crate::ripemd::{RIPEMDState, Ripemd};
+pub use crate::rsync::{rsync_block_collision, rsync_checksum};
+pub use crate::search::{PathCompletion, Signed};
+pub use crate::state::{ExtendedMD4State, ExtendedMd4, MD4State};
+pub use crate::trace::{step_name, trace, trace_extended, ConditionCheck, StepTrace, Trace};
+pub use crate::verify::{is_wang_differential, verify, Verification};
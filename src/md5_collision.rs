@@ -0,0 +1,664 @@
+use crate::builder::CancelToken;
+use crate::common::*;
+use crate::compression::CollisionSearch;
+use crate::md5_state::{boolean, shift, step, step_t, word, MD5State};
+use byteorder::{ByteOrder, LE};
+use lazy_static::lazy_static;
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+
+/// The first MD5 collision, published by Wang and Yu: two blocks per message,
+/// hex-encoded. `MD5CollisionFinder` follows the differential path of this pair.
+pub const WANG_MD5_PAIR: [&str; 2] = [
+    concat!(
+        "d131dd02c5e6eec4693d9a0698aff95c2fcab58712467eab4004583eb8fb7f89",
+        "55ad340609f4b30283e488832571415a085125e8f7cdc99fd91dbdf280373c5b",
+        "d8823e3156348f5bae6dacd436c919c6dd53e2b487da03fd02396306d248cda0",
+        "e99f33420f577ee8ce54b67080a80d1ec69821bcb6a8839396f9652b6ff72a70",
+    ),
+    concat!(
+        "d131dd02c5e6eec4693d9a0698aff95c2fcab50712467eab4004583eb8fb7f89",
+        "55ad340609f4b30283e4888325f1415a085125e8f7cdc99fd91dbd7280373c5b",
+        "d8823e3156348f5bae6dacd436c919c6dd53e23487da03fd02396306d248cda0",
+        "e99f33420f577ee8ce54b67080280d1ec69821bcb6a8839396f965ab6ff72a70",
+    ),
+];
+
+// Step values are indexed by position: 0..4 hold the initial values in the
+// order steps use them (a, d, c, b), and step i writes position i + 4
+
+/// Bit conditions on one step value, as masks
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct Conditions {
+    zero: u32,
+    one: u32,
+    /// Bits equal to, or different from, the same bit of the previous value
+    eq_prev: u32,
+    ne_prev: u32,
+    /// Same, relative to the value two positions before
+    eq_prev2: u32,
+    ne_prev2: u32,
+}
+
+impl Conditions {
+    fn holds(&self, q: u32, prev: u32, prev2: u32) -> bool {
+        q & self.zero == 0
+            && !q & self.one == 0
+            && (q ^ prev) & self.eq_prev == 0
+            && !(q ^ prev) & self.ne_prev == 0
+            && (q ^ prev2) & self.eq_prev2 == 0
+            && !(q ^ prev2) & self.ne_prev2 == 0
+    }
+
+    fn mask(&self) -> u32 {
+        self.zero | self.one | self.eq_prev | self.ne_prev | self.eq_prev2 | self.ne_prev2
+    }
+
+    // Fill the free bits of a value with `random`; only exact when no bit
+    // carries more than one kind of condition
+    fn sample(&self, random: u32, prev: u32, prev2: u32) -> u32 {
+        (random & !self.mask())
+            | self.one
+            | (prev & self.eq_prev)
+            | (!prev & self.ne_prev)
+            | (prev2 & self.eq_prev2)
+            | (!prev2 & self.ne_prev2)
+    }
+}
+
+/// One block of the two-block differential path
+struct BlockPath {
+    /// Message word differences (M' - M)
+    differences: [u32; 16],
+    /// XOR and modular difference of every position
+    xor: [u32; 68],
+    diff: [u32; 68],
+    /// Modular difference of each step's sum before the rotation
+    sum_diff: [u32; 64],
+    /// Sufficient conditions on every position
+    conditions: [Conditions; 68],
+    /// The same conditions, rewritten so that every bit of positions 0..20
+    /// carries at most one kind and can be sampled directly
+    sampling: [Conditions; 68],
+    /// Bits of positions 13 and 12 (steps 10 and 9) which can be flipped
+    /// without changing any other step value of round 1, when the following
+    /// positions have the right values
+    tunnels: [u32; 2],
+}
+
+// Cost of placing a condition on a position: initial values cannot be chosen,
+// round 1 values are chosen freely, later ones are left to chance
+fn cost(position: usize) -> u32 {
+    match position {
+        0..=3 => 100,
+        4..=19 => 1,
+        _ => 10,
+    }
+}
+
+// Conditions placed on the inputs (x, y, z) of one boolean function bit:
+// absolute values, and equalities for the pairs (x, y), (y, z), (x, z)
+type BitChoice = ([Option<u32>; 3], [Option<bool>; 3]);
+
+const PAIRS: [(usize, usize); 3] = [(0, 1), (1, 2), (0, 2)];
+
+// Find the cheapest conditions which make bit `bit` of the boolean function
+// of step `i` take the difference it has in the given pair of inputs
+fn bit_choice(i: usize, bit: usize, inputs: [[u32; 3]; 2], positions: [usize; 3]) -> BitChoice {
+    let v: Vec<u32> = (0..3).map(|k| (inputs[0][k] >> bit) & 1).collect();
+    let d: Vec<u32> = (0..3)
+        .map(|k| ((inputs[0][k] ^ inputs[1][k]) >> bit) & 1)
+        .collect();
+    let output = |x: &[u32]| boolean(i, x[0], x[1], x[2]) & 1;
+    let want = output(&[v[0] ^ d[0], v[1] ^ d[1], v[2] ^ d[2]]) as i32 - output(&v) as i32;
+
+    // Whether an assignment of the first message's input bits gives the same
+    // output difference; only its presence counts in bit 31
+    let gives = |a: &[u32]| {
+        let o1 = output(a) as i32;
+        let o2 = output(&[a[0] ^ d[0], a[1] ^ d[1], a[2] ^ d[2]]) as i32;
+        if bit == 31 {
+            (o1 != o2) == (want != 0)
+        } else {
+            o2 - o1 == want
+        }
+    };
+
+    let absolute = [None, Some(0), Some(1)];
+    let relative = [None, Some(true), Some(false)];
+    let mut best: Option<(u32, BitChoice)> = None;
+    for c in 0..729 {
+        let digit = |k: u32| (c / 3u32.pow(k)) % 3;
+        let abs = [
+            absolute[digit(0) as usize],
+            absolute[digit(1) as usize],
+            absolute[digit(2) as usize],
+        ];
+        let rel = [
+            relative[digit(3) as usize],
+            relative[digit(4) as usize],
+            relative[digit(5) as usize],
+        ];
+
+        let weight = (0..3)
+            .filter(|&k| abs[k].is_some())
+            .map(|k| cost(positions[k]))
+            .chain(
+                (0..3)
+                    .filter(|&p| rel[p].is_some())
+                    .map(|p| cost(positions[PAIRS[p].0])),
+            )
+            .sum::<u32>();
+        if best.as_ref().is_some_and(|(w, _)| weight >= *w) {
+            continue;
+        }
+
+        let allows = |a: &[u32]| {
+            (0..3).all(|k| abs[k].is_none_or(|x| a[k] == x))
+                && (0..3).all(|p| {
+                    let (x, y) = PAIRS[p];
+                    rel[p].is_none_or(|eq| (a[x] == a[y]) == eq)
+                })
+        };
+        let valid = allows(&v)
+            && (0..8u32)
+                .map(|a| [a & 1, (a >> 1) & 1, (a >> 2) & 1])
+                .filter(|a| allows(a))
+                .all(|a| gives(&a));
+        if valid {
+            best = Some((weight, (abs, rel)));
+        }
+    }
+    best.unwrap().1
+}
+
+impl BlockPath {
+    /// Derive the path followed by one block of a colliding pair, starting
+    /// from the given chaining states
+    fn derive(init: [MD5State; 2], blocks: [&[u8]; 2]) -> BlockPath {
+        let mut data = [U32Block::default(); 2];
+        let mut q = [[0u32; 68]; 2];
+        for k in 0..2 {
+            LE::read_u32_into(blocks[k], &mut data[k]);
+            q[k][..4].copy_from_slice(&init[k].initial_values());
+            q[k][4..].copy_from_slice(&init[k].step_values(&data[k]));
+        }
+
+        let mut path = BlockPath {
+            differences: [0; 16],
+            xor: [0; 68],
+            diff: [0; 68],
+            sum_diff: [0; 64],
+            conditions: [Conditions::default(); 68],
+            sampling: [Conditions::default(); 68],
+            tunnels: [0; 2],
+        };
+        for (w, difference) in path.differences.iter_mut().enumerate() {
+            *difference = data[1][w].wrapping_sub(data[0][w]);
+        }
+        for (p, (&q1, &q2)) in q[0].iter().zip(&q[1]).enumerate() {
+            path.xor[p] = q1 ^ q2;
+            path.diff[p] = q2.wrapping_sub(q1);
+
+            // Differences below bit 31 are signed
+            let signed = path.xor[p] & 0x7fff_ffff;
+            path.conditions[p].one |= signed & q1;
+            path.conditions[p].zero |= signed & !q1;
+        }
+
+        for i in 0..64 {
+            let p = i + 4;
+            let sum = |k: usize| q[k][p].wrapping_sub(q[k][p - 1]).rotate_right(shift(i));
+            path.sum_diff[i] = sum(1).wrapping_sub(sum(0));
+
+            let positions = [p - 1, p - 2, p - 3];
+            let inputs = [
+                [q[0][p - 1], q[0][p - 2], q[0][p - 3]],
+                [q[1][p - 1], q[1][p - 2], q[1][p - 3]],
+            ];
+            for bit in 0..32 {
+                if (0..3).all(|k| path.xor[positions[k]] & (1 << bit) == 0) {
+                    continue;
+                }
+
+                let (abs, rel) = bit_choice(i, bit, inputs, positions);
+                for k in 0..3 {
+                    let c = &mut path.conditions[positions[k]];
+                    match abs[k] {
+                        Some(0) => c.zero |= 1 << bit,
+                        Some(_) => c.one |= 1 << bit,
+                        None => (),
+                    }
+                }
+                // (x, y) and (y, z) are consecutive positions, (x, z) are two apart
+                for (pair, eq) in rel.iter().enumerate() {
+                    let c = &mut path.conditions[positions[PAIRS[pair].0]];
+                    match (pair, eq) {
+                        (2, Some(true)) => c.eq_prev2 |= 1 << bit,
+                        (2, Some(false)) => c.ne_prev2 |= 1 << bit,
+                        (_, Some(true)) => c.eq_prev |= 1 << bit,
+                        (_, Some(false)) => c.ne_prev |= 1 << bit,
+                        (_, None) => (),
+                    }
+                }
+            }
+        }
+
+        path.sampling = path.conditions;
+        path.rewrite(&q[0]);
+        path.add_tunnels(&q[0]);
+        path
+    }
+
+    // Rewrite the conditions of positions 0..20 into an equivalent form with
+    // at most one kind of condition per bit, pushing relations which overlap
+    // another condition down to the earlier position as absolute values
+    fn rewrite(&mut self, q: &[u32; 68]) {
+        for p in (2..20).rev() {
+            let c = self.sampling[p];
+            let absolute = c.zero | c.one;
+
+            // An absolute value turns relations into absolute values below
+            let pushed = absolute & (c.eq_prev | c.ne_prev);
+            self.sampling[p - 1].zero |= pushed & !q[p - 1];
+            self.sampling[p - 1].one |= pushed & q[p - 1];
+            let pushed2 = absolute & (c.eq_prev2 | c.ne_prev2);
+            self.sampling[p - 2].zero |= pushed2 & !q[p - 2];
+            self.sampling[p - 2].one |= pushed2 & q[p - 2];
+
+            // Two relations become a relation between the earlier positions
+            let prev = (c.eq_prev | c.ne_prev) & !absolute;
+            let both = prev & (c.eq_prev2 | c.ne_prev2);
+            let differ = q[p - 1] ^ q[p - 2];
+            self.sampling[p - 1].eq_prev |= both & !differ;
+            self.sampling[p - 1].ne_prev |= both & differ;
+
+            let s = &mut self.sampling[p];
+            s.eq_prev &= !absolute;
+            s.ne_prev &= !absolute;
+            s.eq_prev2 &= !(absolute | both);
+            s.ne_prev2 &= !(absolute | both);
+        }
+    }
+
+    // Free bits of position 12 are a tunnel where position 13 is zero and
+    // position 14 is one: the boolean functions of steps 11 and 12 then ignore
+    // them and only message words 8, 9 and 12 change. Free bits of position 13
+    // are one where position 14 is zero, changing words 9, 10, 12 and 13.
+    // Conditions are added to open more of them, but only those which the pair
+    // the path comes from satisfies: arbitrary ones could contradict the
+    // rotations.
+    fn add_tunnels(&mut self, q: &[u32; 68]) {
+        let s = &mut self.sampling;
+        let only = |c: &Conditions, bits: u32| c.mask() & !bits;
+        let q9 = !s[12].mask() & !only(&s[13], s[13].zero) & !only(&s[14], s[14].one);
+        s[13].zero |= q9 & !q[13] & q[14];
+        s[14].one |= q9 & !q[13] & q[14];
+
+        let q10 = !s[13].mask() & !only(&s[14], s[14].zero) & !(s[15].eq_prev2 | s[15].ne_prev2);
+        s[14].zero |= q10 & !q[14];
+        self.tunnels = [q10, q9];
+    }
+
+    // Whether the rotation of step i carries the sum difference into the
+    // expected step value difference
+    fn rotation_holds(&self, i: usize, q: &[u32; 68]) -> bool {
+        let p = i + 4;
+        let s = shift(i);
+        let sum = q[p].wrapping_sub(q[p - 1]).rotate_right(s);
+        let rotated = sum
+            .wrapping_add(self.sum_diff[i])
+            .rotate_left(s)
+            .wrapping_sub(sum.rotate_left(s));
+        rotated == self.diff[p].wrapping_sub(self.diff[p - 1])
+    }
+
+    // Whether position p satisfies its conditions
+    fn holds(&self, p: usize, q: &[u32; 68]) -> bool {
+        let prev = if p >= 1 { q[p - 1] } else { 0 };
+        let prev2 = if p >= 2 { q[p - 2] } else { 0 };
+        self.sampling[p].holds(q[p], prev, prev2)
+    }
+}
+
+// Tunnels in the order they are used: the position they flip, and the range
+// of step indices to compute after each flip
+const TUNNELS: [(usize, usize, usize); 2] = [(13, 21, 24), (12, 24, 64)];
+
+lazy_static! {
+    static ref MD5_PATH: [BlockPath; 2] = {
+        let pair: Vec<Vec<u8>> = WANG_MD5_PAIR
+            .iter()
+            .map(|m| hex::decode(m).unwrap())
+            .collect();
+        let init = MD5State::new();
+        let first = BlockPath::derive([init; 2], [&pair[0][..64], &pair[1][..64]]);
+
+        let next = |k: usize| init.process_block(U8Block::from_slice(&pair[k][..64]));
+        let second = BlockPath::derive([next(0), next(1)], [&pair[0][64..], &pair[1][64..]]);
+        [first, second]
+    };
+}
+
+/// Search for a two-block MD5 collision from a given chaining state, following
+/// the path of `WANG_MD5_PAIR`. Round 1 is satisfied by choosing step values,
+/// steps 17 and 20 of the first block by choosing message words, and tunnels
+/// on steps 9 and 10 give many candidates sharing the first 21 steps.
+pub struct MD5CollisionFinder {
+    init: MD5State,
+    /// The first blocks, once found
+    first: Option<(U8Block, U8Block)>,
+    q: [u32; 68],
+    data: U32Block,
+    rng: StdRng,
+    cancel: CancelToken,
+}
+
+impl MD5CollisionFinder {
+    pub fn from(state: MD5State) -> MD5CollisionFinder {
+        MD5CollisionFinder {
+            init: state,
+            first: None,
+            q: [0; 68],
+            data: Default::default(),
+            rng: StdRng::from_entropy(),
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// Draw the step values from a generator seeded with `seed`, so that the
+    /// same seed finds the same collision
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    // Compute step i from the current message and check it against the path
+    fn compute(&mut self, path: &BlockPath, i: usize) -> bool {
+        let p = i + 4;
+        let mut window = [0u32; 4];
+        window.copy_from_slice(&self.q[i..p]);
+        self.q[p] = step(i, window, self.data[word(i)]);
+        path.holds(p, &self.q) && path.rotation_holds(i, &self.q)
+    }
+
+    // Choose the value of position p satisfying its conditions, and derive
+    // the message word of its step
+    fn choose(&mut self, path: &BlockPath, p: usize) -> bool {
+        let i = p - 4;
+        for _ in 0..16 {
+            let c = path.sampling[p];
+            self.q[p] = c.sample(self.rng.gen(), self.q[p - 1], self.q[p - 2]);
+            if path.holds(p, &self.q) && path.rotation_holds(i, &self.q) {
+                let mut window = [0u32; 4];
+                window.copy_from_slice(&self.q[i..p]);
+                self.data[word(i)] = step_t(i, window, self.q[p]);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Recompute message words 8 to 13 from the step values, after a tunnel
+    // changed one of them
+    fn update_words(&mut self) {
+        let mut window = [0u32; 4];
+        for i in 8..14 {
+            window.copy_from_slice(&self.q[i..i + 4]);
+            self.data[i] = step_t(i, window, self.q[i + 4]);
+        }
+    }
+
+    // Bits of tunnel k which the current step values open
+    fn open_bits(&self, path: &BlockPath, k: usize) -> u32 {
+        match k {
+            0 => path.tunnels[0] & !self.q[14],
+            _ => path.tunnels[1] & !self.q[13] & self.q[14],
+        }
+    }
+
+    // Try every combination of the bits of tunnel k, computing the steps it
+    // changes up to the next tunnel. The last one finishes the block, which
+    // while the first block is searched must also lead to the second path.
+    fn use_tunnel(&mut self, path: &BlockPath, k: usize) -> bool {
+        let (p, from, to) = TUNNELS[k];
+        let tunnel = self.open_bits(path, k);
+        let base = self.q[p];
+        let mut flip = 0u32;
+        loop {
+            self.q[p] = base ^ flip;
+            self.update_words();
+            if path.rotation_holds(p - 4, &self.q)
+                && path.rotation_holds(p - 3, &self.q)
+                && (from..to).all(|i| self.compute(path, i))
+                && if k + 1 < TUNNELS.len() {
+                    self.use_tunnel(path, k + 1)
+                } else {
+                    self.first.is_some() || self.fits_second(path)
+                }
+            {
+                return true;
+            }
+
+            // Next subset of the tunnel bits
+            flip = flip.wrapping_sub(tunnel) & tunnel;
+            if flip == 0 || self.cancel.is_cancelled() {
+                self.q[p] = base;
+                self.update_words();
+                return false;
+            }
+        }
+    }
+
+    fn blocks(&self, path: &BlockPath) -> (U8Block, U8Block) {
+        let mut b1 = U8Block::default();
+        LE::write_u32_into(&self.data, &mut b1);
+
+        let mut data = self.data;
+        for w in 0..16 {
+            data[w] = data[w].wrapping_add(path.differences[w]);
+        }
+        let mut b2 = U8Block::default();
+        LE::write_u32_into(&data, &mut b2);
+        (b1, b2)
+    }
+
+    // Whether the chaining states after the first blocks fit the second path
+    fn fits_second(&self, path: &BlockPath) -> bool {
+        let (b1, b2) = self.blocks(path);
+        let mut q = [0u32; 68];
+        q[..4].copy_from_slice(&self.init.process_block(&b1).initial_values());
+        let q2 = self.init.process_block(&b2).initial_values();
+
+        let next = &MD5_PATH[1];
+        (0..4).all(|p| q[p] ^ q2[p] == next.xor[p] && next.holds(p, &q))
+    }
+
+    fn find_first(&mut self) -> Option<(U8Block, U8Block)> {
+        let path = &MD5_PATH[0];
+        self.q[..4].copy_from_slice(&self.init.initial_values());
+
+        // Round 1 from step 3 on; steps 1 and 2 carry no condition and are
+        // chosen last
+        for p in 6..20 {
+            if !self.choose(path, p) {
+                return None;
+            }
+        }
+
+        for _ in 0..64 {
+            if self.cancel.is_cancelled() {
+                return None;
+            }
+
+            // Step 17 fixes message word 1, steps 18 and 19 only use words
+            // which are already known
+            if !self.choose(path, 20) || !self.compute(path, 17) || !self.compute(path, 18) {
+                continue;
+            }
+
+            for _ in 0..256 {
+                // Step 20 fixes message word 0, hence steps 1 and 2
+                if !self.choose(path, 23) {
+                    continue;
+                }
+                let mut window = [0u32; 4];
+                for i in 0..2 {
+                    window.copy_from_slice(&self.q[i..i + 4]);
+                    self.q[i + 4] = step(i, window, self.data[i]);
+                }
+                for i in 2..6 {
+                    window.copy_from_slice(&self.q[i..i + 4]);
+                    self.data[i] = step_t(i, window, self.q[i + 4]);
+                }
+                if (0..6).all(|i| path.holds(i + 4, &self.q) && path.rotation_holds(i, &self.q))
+                    && self.compute(path, 20)
+                    && self.use_tunnel(path, 0)
+                {
+                    return Some(self.blocks(path));
+                }
+            }
+        }
+        None
+    }
+
+    fn find_second(&mut self, init: MD5State) -> Option<(U8Block, U8Block)> {
+        let path = &MD5_PATH[1];
+        self.q[..4].copy_from_slice(&init.initial_values());
+
+        if !(4..19).all(|p| self.choose(path, p)) {
+            return None;
+        }
+
+        // Step 16 only fixes message word 15, which round 2 uses from step 23
+        // on, so it is chosen again for every attempt at steps 17 to 21
+        for _ in 0..1 << 16 {
+            if self.cancel.is_cancelled() {
+                return None;
+            }
+            if self.choose(path, 19)
+                && (16..21).all(|i| self.compute(path, i))
+                && self.use_tunnel(path, 0)
+            {
+                return Some(self.blocks(path));
+            }
+        }
+        None
+    }
+
+    /// Make one attempt at the block currently searched for. Returns both
+    /// two-block messages once the second block is found.
+    pub fn find_once(&mut self) -> Option<([U8Block; 2], [U8Block; 2])> {
+        match self.first {
+            None => {
+                self.first = self.find_first();
+                None
+            }
+            Some((b1, b2)) => {
+                let init = self.init.process_block(&b1);
+                let (c1, c2) = self.find_second(init)?;
+                Some(([b1, c1], [b2, c2]))
+            }
+        }
+    }
+}
+
+impl CollisionSearch for MD5CollisionFinder {
+    type State = MD5State;
+    type Options = ();
+
+    fn new(state: MD5State, _: &()) -> MD5CollisionFinder {
+        MD5CollisionFinder::from(state)
+    }
+
+    fn find_messages(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (m1, m2) = self.find_once()?;
+        Some((m1.concat(), m2.concat()))
+    }
+
+    fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+}
+
+#[cfg(test)]
+mod md5_collision_tests {
+    use crate::md5_collision::*;
+    use md5::{Digest, Md5};
+
+    fn wang_pair() -> Vec<Vec<u8>> {
+        WANG_MD5_PAIR
+            .iter()
+            .map(|m| hex::decode(m).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn derived_path_fits_wang_pair() {
+        let pair = wang_pair();
+        let mut init = MD5State::new();
+        for (block, path) in MD5_PATH.iter().enumerate() {
+            let range = 64 * block..64 * (block + 1);
+            let mut data = U32Block::default();
+            LE::read_u32_into(&pair[0][range], &mut data);
+
+            let mut q = [0u32; 68];
+            q[..4].copy_from_slice(&init.initial_values());
+            q[4..].copy_from_slice(&init.step_values(&data));
+            for p in 0..68 {
+                let (prev, prev2) = (q[p.max(1) - 1], q[p.max(2) - 2]);
+                assert!(path.conditions[p].holds(q[p], prev, prev2));
+                assert!(path.holds(p, &q), "block {} position {}", block, p);
+            }
+            for i in 0..64 {
+                assert!(path.rotation_holds(i, &q));
+            }
+            init = init.process_u32array(&data);
+        }
+    }
+
+    #[test]
+    fn tunnels_keep_other_steps() {
+        let pair = wang_pair();
+        let path = &MD5_PATH[1];
+        let init = MD5State::new().process_block(U8Block::from_slice(&pair[0][..64]));
+        let mut finder = MD5CollisionFinder::from(init);
+        LE::read_u32_into(&pair[0][64..], &mut finder.data);
+        finder.q[..4].copy_from_slice(&init.initial_values());
+        finder.q[4..].copy_from_slice(&init.step_values(&finder.data));
+        let original = finder.q;
+
+        for (k, &(p, from, _)) in TUNNELS.iter().enumerate() {
+            let open = finder.open_bits(path, k);
+            assert_ne!(open, 0);
+            finder.q = original;
+            finder.q[p] ^= open;
+            finder.update_words();
+
+            // Round 1 is unchanged but for the flipped position, and so are
+            // the steps computed before the tunnel
+            let mut q = [0u32; 68];
+            q[..4].copy_from_slice(&init.initial_values());
+            q[4..].copy_from_slice(&init.step_values(&finder.data));
+            for position in (0..from + 4).filter(|&position| position != p) {
+                assert_eq!(q[position], original[position], "position {}", position);
+            }
+            assert_eq!(q[p], original[p] ^ open);
+        }
+    }
+
+    // Minutes in a release build: cargo test --release -- --ignored
+    #[test]
+    #[ignore]
+    fn find_collision() {
+        let mut finder = MD5CollisionFinder::from(MD5State::new());
+        finder.set_seed(1);
+        let (m1, m2) = loop {
+            if let Some(pair) = finder.find_once() {
+                break pair;
+            }
+        };
+        let (m1, m2) = (m1.concat(), m2.concat());
+        assert_ne!(m1, m2);
+        assert_eq!(Md5::digest(&m1), Md5::digest(&m2));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::common::*;
+use crate::compression::CompressionState;
+use byteorder::{ByteOrder, LE};
+
+// Additive constant of each of the 64 steps, floor(abs(sin(i + 1)) * 2^32)
+pub(crate) const AC: [u32; 64] = [
+    0xd76a_a478,
+    0xe8c7_b756,
+    0x2420_70db,
+    0xc1bd_ceee,
+    0xf57c_0faf,
+    0x4787_c62a,
+    0xa830_4613,
+    0xfd46_9501,
+    0x6980_98d8,
+    0x8b44_f7af,
+    0xffff_5bb1,
+    0x895c_d7be,
+    0x6b90_1122,
+    0xfd98_7193,
+    0xa679_438e,
+    0x49b4_0821,
+    0xf61e_2562,
+    0xc040_b340,
+    0x265e_5a51,
+    0xe9b6_c7aa,
+    0xd62f_105d,
+    0x0244_1453,
+    0xd8a1_e681,
+    0xe7d3_fbc8,
+    0x21e1_cde6,
+    0xc337_07d6,
+    0xf4d5_0d87,
+    0x455a_14ed,
+    0xa9e3_e905,
+    0xfcef_a3f8,
+    0x676f_02d9,
+    0x8d2a_4c8a,
+    0xfffa_3942,
+    0x8771_f681,
+    0x6d9d_6122,
+    0xfde5_380c,
+    0xa4be_ea44,
+    0x4bde_cfa9,
+    0xf6bb_4b60,
+    0xbebf_bc70,
+    0x289b_7ec6,
+    0xeaa1_27fa,
+    0xd4ef_3085,
+    0x0488_1d05,
+    0xd9d4_d039,
+    0xe6db_99e5,
+    0x1fa2_7cf8,
+    0xc4ac_5665,
+    0xf429_2244,
+    0x432a_ff97,
+    0xab94_23a7,
+    0xfc93_a039,
+    0x655b_59c3,
+    0x8f0c_cc92,
+    0xffef_f47d,
+    0x8584_5dd1,
+    0x6fa8_7e4f,
+    0xfe2c_e6e0,
+    0xa301_4314,
+    0x4e08_11a1,
+    0xf753_7e82,
+    0xbd3a_f235,
+    0x2ad7_d2bb,
+    0xeb86_d391,
+];
+
+// Rotation of each step, which only depends on the round and the step modulo 4
+pub(crate) const SHIFT: [[u32; 4]; 4] = [
+    [7, 12, 17, 22],
+    [5, 9, 14, 20],
+    [4, 11, 16, 23],
+    [6, 10, 15, 21],
+];
+
+/// Message word used by step `i`
+pub(crate) fn word(i: usize) -> usize {
+    match i / 16 {
+        0 => i,
+        1 => (5 * i + 1) % 16,
+        2 => (3 * i + 5) % 16,
+        _ => (7 * i) % 16,
+    }
+}
+
+pub(crate) fn shift(i: usize) -> u32 {
+    SHIFT[i / 16][i % 4]
+}
+
+/// Boolean function of step `i` applied to the three previous step values
+pub(crate) fn boolean(i: usize, x: u32, y: u32, z: u32) -> u32 {
+    match i / 16 {
+        0 => (x & y) | (!x & z),
+        1 => (x & z) | (y & !z),
+        2 => x ^ y ^ z,
+        _ => y ^ (x | !z),
+    }
+}
+
+/// Compute step `i` from the four previous step values, oldest first
+pub(crate) fn step(i: usize, q: [u32; 4], m: u32) -> u32 {
+    let t = q[0]
+        .wrapping_add(boolean(i, q[3], q[2], q[1]))
+        .wrapping_add(m)
+        .wrapping_add(AC[i]);
+    q[3].wrapping_add(t.rotate_left(shift(i)))
+}
+
+/// Recover the message word of step `i` from the step value it produced
+pub(crate) fn step_t(i: usize, q: [u32; 4], v: u32) -> u32 {
+    v.wrapping_sub(q[3])
+        .rotate_right(shift(i))
+        .wrapping_sub(q[0])
+        .wrapping_sub(boolean(i, q[3], q[2], q[1]))
+        .wrapping_sub(AC[i])
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MD5State {
+    pub s: [u32; 4],
+}
+
+impl Default for MD5State {
+    fn default() -> Self {
+        MD5State::new()
+    }
+}
+
+impl MD5State {
+    pub fn new() -> MD5State {
+        MD5State {
+            s: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476],
+        }
+    }
+
+    /// Rebuild the chaining state from a (little-endian) MD5 digest
+    pub fn from_digest(digest: &[u8; 16]) -> MD5State {
+        let mut s = [0u32; 4];
+        LE::read_u32_into(digest, &mut s);
+        MD5State { s }
+    }
+
+    /// Serialize the chaining state as an MD5 digest
+    pub fn digest(&self) -> [u8; 16] {
+        let mut digest = [0u8; 16];
+        LE::write_u32_into(&self.s, &mut digest);
+        digest
+    }
+
+    pub fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+
+    /// Apply every complete block of `input` and return how many bytes were used
+    pub fn apply_blocks(&mut self, input: &[u8]) -> usize {
+        let blocks = input.chunks_exact(64);
+        let used = input.len() - blocks.remainder().len();
+        for block in blocks {
+            self.apply_block(U8Block::from_slice(block));
+        }
+        used
+    }
+
+    pub fn process_block(&self, input: &U8Block) -> MD5State {
+        /* Copy block into data. */
+        let mut data = U32Block::default();
+        LE::read_u32_into(input, &mut data);
+
+        self.process_u32array(&data)
+    }
+
+    /// The initial values in the order steps use them: a, d, c, b
+    pub fn initial_values(&self) -> [u32; 4] {
+        [self.s[0], self.s[3], self.s[2], self.s[1]]
+    }
+
+    /// Compute the value written by each of the 64 steps, in order (a1, d1, c1, b1, a2, ...)
+    pub fn step_values(&self, data: &U32Block) -> Vec<u32> {
+        let mut q = self.initial_values();
+        let mut steps = Vec::with_capacity(64);
+        for i in 0..64 {
+            let v = step(i, q, data[word(i)]);
+            q = [q[1], q[2], q[3], v];
+            steps.push(v);
+        }
+        steps
+    }
+
+    pub fn process_u32array(&self, data: &U32Block) -> MD5State {
+        let steps = self.step_values(data);
+
+        MD5State {
+            s: [
+                self.s[0].wrapping_add(steps[60]),
+                self.s[1].wrapping_add(steps[63]),
+                self.s[2].wrapping_add(steps[62]),
+                self.s[3].wrapping_add(steps[61]),
+            ],
+        }
+    }
+}
+
+impl CompressionState for MD5State {
+    type BlockSize = U64;
+
+    fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+}
+
+#[cfg(test)]
+mod md5_state_tests {
+    use crate::md5_state::*;
+    use md5::{Digest, Md5};
+
+    #[test]
+    fn matches_reference() {
+        let message: Vec<u8> = (0..200u32).map(|i| (i * 13) as u8).collect();
+
+        let mut hasher = Md5::new();
+        hasher.input(&message);
+        let expected = hasher.result();
+
+        // Pad by hand: 0x80, zeros, then the bit length
+        let mut padded = message.clone();
+        padded.push(0x80);
+        while padded.len() % 64 != 56 {
+            padded.push(0);
+        }
+        let mut length = [0u8; 8];
+        LE::write_u64(&mut length, message.len() as u64 * 8);
+        padded.extend_from_slice(&length);
+
+        let mut state = MD5State::new();
+        assert_eq!(state.apply_blocks(&padded), padded.len());
+        assert_eq!(state.digest()[..], expected[..]);
+    }
+
+    #[test]
+    fn message_words_round_trip() {
+        let init = MD5State::new().initial_values();
+        for i in 0..64 {
+            let m = (i as u32).wrapping_mul(0x9e37_79b9);
+            let v = step(i, init, m);
+            assert_eq!(step_t(i, init, v), m);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::builder::Builder;
+
+/// One of several independent collisions in the same file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollisionPoint {
+    pub offset: u64,
+    pub padding: Vec<u8>,
+    pub m1: Vec<u8>,
+    pub m2: Vec<u8>,
+}
+
+impl CollisionPoint {
+    /// Offset just past the colliding message
+    pub fn end(&self) -> u64 {
+        self.offset + (self.padding.len() + self.m1.len()) as u64
+    }
+}
+
+/// Find an MD4 collision at each of the increasing `offsets` of `input`. The
+/// colliding blocks overwrite the input from their offset on, and the input
+/// between the end of one collision and the next offset is shared, so that
+/// any choice of message at each point gives the same digest.
+pub fn multi_collide(input: &[u8], offsets: &[u64]) -> Result<Vec<CollisionPoint>, &'static str> {
+    let mut builder = Builder::new();
+    let mut points: Vec<CollisionPoint> = Vec::new();
+    let mut position = 0;
+    for &offset in offsets {
+        if offset > input.len() as u64 {
+            return Err("offset is larger than file size");
+        }
+        if offset < position {
+            return Err("offsets must increase and not overlap the previous collision");
+        }
+
+        builder.input(&input[position as usize..offset as usize]);
+        let (padding, m1, m2) = builder.build().map_err(|_| "collision search failed")?;
+
+        // Both messages lead to the same state, so continue after either
+        builder.input(&m1);
+        let point = CollisionPoint {
+            offset,
+            padding,
+            m1,
+            m2,
+        };
+        position = point.end();
+        points.push(point);
+    }
+    Ok(points)
+}
+
+/// The input with the collisions applied, taking m2 at point i when
+/// `choice[i]` is set and m1 otherwise
+pub fn combine(input: &[u8], points: &[CollisionPoint], choice: &[bool]) -> Vec<u8> {
+    let end = points.last().map_or(0, |p| p.end() as usize);
+    let mut output = input.to_vec();
+    output.resize(output.len().max(end), 0);
+    for (point, &second) in points.iter().zip(choice) {
+        let m = if second { &point.m2 } else { &point.m1 };
+        let start = point.offset as usize;
+        let block = [&point.padding[..], m].concat();
+        output[start..start + block.len()].copy_from_slice(&block);
+    }
+    output
+}
+
+#[cfg(test)]
+mod multi_tests {
+    use crate::multi::*;
+    use md4::{Digest, Md4};
+
+    #[test]
+    fn every_combination_collides() {
+        let input: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        let points = multi_collide(&input, &[10, 200]).unwrap();
+        assert_eq!(points[0].end(), 128);
+        assert_eq!(points[1].end(), 320);
+
+        let digest = Md4::digest(&combine(&input, &points, &[false, false]));
+        for choice in &[[false, true], [true, false], [true, true]] {
+            let output = combine(&input, &points, choice);
+            assert_eq!(output.len(), 320);
+            assert_eq!(&output[128..200], &input[128..200]);
+            assert_eq!(Md4::digest(&output), digest);
+        }
+
+        assert!(multi_collide(&input, &[10, 100]).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+/// Where a collision starts in the input, resolved once the input is known
+#[derive(Debug, Clone, PartialEq)]
+pub enum Offset {
+    /// A byte offset, decimal or 0x-prefixed hex
+    Absolute(u64),
+    /// Bytes before the end of the input, written `-N` or `end-N`
+    FromEnd(u64),
+    /// The end of the input, written `end`
+    End,
+    /// Just after the first occurrence of a byte string, written `after:HEX`
+    /// or `after-text:TEXT`
+    After(Vec<u8>),
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("invalid offset {}: {}", s, e))
+}
+
+impl FromStr for Offset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Offset, String> {
+        if s == "end" {
+            Ok(Offset::End)
+        } else if let Some(text) = s.strip_prefix("after-text:") {
+            if text.is_empty() {
+                return Err(String::from("the anchor is empty"));
+            }
+            Ok(Offset::After(text.as_bytes().to_vec()))
+        } else if let Some(bytes) = s.strip_prefix("after:") {
+            match hex::decode(bytes) {
+                Ok(ref anchor) if anchor.is_empty() => Err(String::from("the anchor is empty")),
+                Ok(anchor) => Ok(Offset::After(anchor)),
+                Err(e) => Err(format!("invalid hex anchor {}: {}", bytes, e)),
+            }
+        } else if let Some(n) = s.strip_prefix("end-").or_else(|| s.strip_prefix('-')) {
+            parse_number(n).map(Offset::FromEnd)
+        } else {
+            parse_number(s).map(Offset::Absolute)
+        }
+    }
+}
+
+impl Offset {
+    /// Turn the offset into a byte count within `input`, `len` bytes long
+    pub fn resolve(&self, input: &mut (impl Read + Seek), len: u64) -> Result<u64, &'static str> {
+        let offset = match self {
+            Offset::Absolute(n) => *n,
+            Offset::FromEnd(n) => len
+                .checked_sub(*n)
+                .ok_or("offset is before the start of the file")?,
+            Offset::End => len,
+            Offset::After(anchor) => {
+                let mut data = Vec::new();
+                input
+                    .seek(SeekFrom::Start(0))
+                    .and_then(|_| input.read_to_end(&mut data))
+                    .map_err(|_| "failed to read input")?;
+                let start = data
+                    .windows(anchor.len())
+                    .position(|w| w == &anchor[..])
+                    .ok_or("anchor not found in input")?;
+                (start + anchor.len()) as u64
+            }
+        };
+
+        if offset > len {
+            Err("offset is larger than file size")
+        } else {
+            Ok(offset)
+        }
+    }
+}
+
+#[cfg(test)]
+mod offset_tests {
+    use crate::offset::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parse_offsets() {
+        assert_eq!("4096".parse(), Ok(Offset::Absolute(4096)));
+        assert_eq!("0x1000".parse(), Ok(Offset::Absolute(4096)));
+        assert_eq!("-64".parse(), Ok(Offset::FromEnd(64)));
+        assert_eq!("end-0x40".parse(), Ok(Offset::FromEnd(64)));
+        assert_eq!("end".parse(), Ok(Offset::End));
+        assert_eq!("after:ffd8".parse(), Ok(Offset::After(vec![0xff, 0xd8])));
+        assert_eq!(
+            "after-text:%PDF".parse(),
+            Ok(Offset::After(b"%PDF".to_vec()))
+        );
+        assert!("after:".parse::<Offset>().is_err());
+        assert!("0xg".parse::<Offset>().is_err());
+    }
+
+    #[test]
+    fn resolve_offsets() {
+        let data = b"header: value\nbody".to_vec();
+        let len = data.len() as u64;
+        let mut input = Cursor::new(data);
+        let mut resolve = |s: &str| s.parse::<Offset>().unwrap().resolve(&mut input, len);
+
+        assert_eq!(resolve("0x4"), Ok(4));
+        assert_eq!(resolve("-4"), Ok(14));
+        assert_eq!(resolve("end"), Ok(18));
+        assert_eq!(resolve("after-text:\n"), Ok(14));
+        assert_eq!(resolve("after:3a20"), Ok(8));
+        assert!(resolve("-19").is_err());
+        assert!(resolve("19").is_err());
+        assert!(resolve("after-text:footer").is_err());
+    }
+}
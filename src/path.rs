@@ -0,0 +1,301 @@
+use crate::trace::step_name;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// A condition on one bit of a step value, in the usual generalized notation
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BitCondition {
+    /// `-`: no condition
+    Free,
+    /// `0`: bit is 0 in both messages
+    Zero,
+    /// `1`: bit is 1 in both messages
+    One,
+    /// `u`: bit is 0 in the first message and 1 in the second
+    Up,
+    /// `n`: bit is 1 in the first message and 0 in the second
+    Down,
+    /// `x`: bit differs between the messages
+    Differ,
+    /// `^`: bit equals the same bit of the previous step value
+    Prev,
+    /// `v`: bit equals the same bit of the step value two steps before
+    Prev2,
+    /// `!`: bit differs from the same bit of the previous step value
+    NotPrev,
+    /// `~`: bit differs from the same bit of the step value two steps before
+    NotPrev2,
+}
+
+impl BitCondition {
+    pub fn from_char(c: char) -> Option<BitCondition> {
+        match c {
+            '-' => Some(BitCondition::Free),
+            '0' => Some(BitCondition::Zero),
+            '1' => Some(BitCondition::One),
+            'u' => Some(BitCondition::Up),
+            'n' => Some(BitCondition::Down),
+            'x' => Some(BitCondition::Differ),
+            '^' => Some(BitCondition::Prev),
+            'v' => Some(BitCondition::Prev2),
+            '!' => Some(BitCondition::NotPrev),
+            '~' => Some(BitCondition::NotPrev2),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            BitCondition::Free => '-',
+            BitCondition::Zero => '0',
+            BitCondition::One => '1',
+            BitCondition::Up => 'u',
+            BitCondition::Down => 'n',
+            BitCondition::Differ => 'x',
+            BitCondition::Prev => '^',
+            BitCondition::Prev2 => 'v',
+            BitCondition::NotPrev => '!',
+            BitCondition::NotPrev2 => '~',
+        }
+    }
+}
+
+/// A differential path for the MD4 compression function: message word
+/// differences and bit conditions on each of the 48 step values
+#[derive(Debug, Clone, PartialEq)]
+pub struct DifferentialPath {
+    pub name: String,
+    /// Message word differences (M' - M) as (word index, difference)
+    pub message_differences: Vec<(usize, u32)>,
+    /// Conditions of each step, indexed by bit
+    pub steps: Vec<[BitCondition; 32]>,
+}
+
+// JSON layout of a path, with differences in hex and conditions as strings
+#[derive(Serialize, Deserialize)]
+struct PathJson {
+    name: String,
+    message_differences: Vec<DifferenceJson>,
+    steps: Vec<StepJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DifferenceJson {
+    word: usize,
+    delta: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StepJson {
+    step: String,
+    conditions: String,
+}
+
+lazy_static! {
+    static ref WANG_PATH: DifferentialPath =
+        DifferentialPath::parse(include_str!("../paths/wang.txt")).unwrap();
+}
+
+fn parse_step(name: &str) -> Result<usize, String> {
+    (0..48)
+        .find(|&i| step_name(i as isize) == name)
+        .ok_or_else(|| format!("unknown step {}", name))
+}
+
+fn parse_conditions(s: &str) -> Result<[BitCondition; 32], String> {
+    let chars: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if chars.len() != 32 {
+        return Err(format!("expected 32 bit conditions, found {}", chars.len()));
+    }
+
+    let mut conditions = [BitCondition::Free; 32];
+    for (i, c) in chars.iter().enumerate() {
+        conditions[31 - i] =
+            BitCondition::from_char(*c).ok_or_else(|| format!("unknown bit condition '{}'", c))?;
+    }
+    Ok(conditions)
+}
+
+fn format_conditions(conditions: &[BitCondition; 32]) -> String {
+    conditions.iter().rev().map(|c| c.to_char()).collect()
+}
+
+impl DifferentialPath {
+    /// The path used by Wang et al., which `CollisionFinder` follows by default
+    pub fn wang() -> DifferentialPath {
+        WANG_PATH.clone()
+    }
+
+    fn empty() -> DifferentialPath {
+        DifferentialPath {
+            name: String::new(),
+            message_differences: Vec::new(),
+            steps: vec![[BitCondition::Free; 32]; 48],
+        }
+    }
+
+    /// Parse the text format (see `paths/wang.txt`)
+    pub fn parse(text: &str) -> Result<DifferentialPath, String> {
+        let mut path = DifferentialPath::empty();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let err = |e: String| format!("line {}: {}", n + 1, e);
+
+            match keyword {
+                "name" => path.name = words.collect::<Vec<_>>().join(" "),
+                "dm" => {
+                    let (word, delta) = match (words.next(), words.next()) {
+                        (Some(word), Some(delta)) => (word, delta),
+                        _ => return Err(err(String::from("expected dm <word> <delta>"))),
+                    };
+                    let word = word
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|w| *w < 16)
+                        .ok_or_else(|| err(format!("invalid message word {}", word)))?;
+                    let delta = u32::from_str_radix(delta, 16)
+                        .map_err(|e| err(format!("invalid difference: {}", e)))?;
+                    path.message_differences.push((word, delta));
+                }
+                step => {
+                    let step = parse_step(step).map_err(err)?;
+                    path.steps[step] = parse_conditions(&words.collect::<String>()).map_err(err)?;
+                }
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Parse the JSON format
+    pub fn from_json(json: &str) -> Result<DifferentialPath, String> {
+        let parsed: PathJson = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let mut path = DifferentialPath::empty();
+        path.name = parsed.name;
+
+        for d in parsed.message_differences {
+            if d.word >= 16 {
+                return Err(format!("invalid message word {}", d.word));
+            }
+            let delta = u32::from_str_radix(&d.delta, 16).map_err(|e| e.to_string())?;
+            path.message_differences.push((d.word, delta));
+        }
+        for s in parsed.steps {
+            path.steps[parse_step(&s.step)?] = parse_conditions(&s.conditions)?;
+        }
+
+        Ok(path)
+    }
+
+    /// Load a path from a file, in JSON if it has a `.json` extension and in
+    /// the text format otherwise
+    pub fn load<P: AsRef<Path>>(file: P) -> Result<DifferentialPath, String> {
+        let file = file.as_ref();
+        let content = fs::read_to_string(file)
+            .map_err(|e| format!("failed to read {}: {}", file.display(), e))?;
+
+        if file.extension().and_then(|e| e.to_str()) == Some("json") {
+            DifferentialPath::from_json(&content)
+        } else {
+            DifferentialPath::parse(&content)
+        }
+    }
+
+    // Steps which carry at least one condition
+    fn conditioned_steps(&self) -> impl Iterator<Item = (usize, &[BitCondition; 32])> {
+        self.steps
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.iter().any(|c| *c != BitCondition::Free))
+    }
+
+    /// Render the path in the text format
+    pub fn to_text(&self) -> String {
+        let mut out = format!("name {}\n", self.name);
+        for (word, delta) in &self.message_differences {
+            out += &format!("dm {} {:08x}\n", word, delta);
+        }
+        for (step, conditions) in self.conditioned_steps() {
+            out += &format!(
+                "{} {}\n",
+                step_name(step as isize),
+                format_conditions(conditions)
+            );
+        }
+        out
+    }
+
+    /// Render the path in the JSON format
+    pub fn to_json(&self) -> String {
+        let json = PathJson {
+            name: self.name.clone(),
+            message_differences: self
+                .message_differences
+                .iter()
+                .map(|(word, delta)| DifferenceJson {
+                    word: *word,
+                    delta: format!("{:08x}", delta),
+                })
+                .collect(),
+            steps: self
+                .conditioned_steps()
+                .map(|(step, conditions)| StepJson {
+                    step: step_name(step as isize),
+                    conditions: format_conditions(conditions),
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&json).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod path_tests {
+    use crate::collision::WANG_DIFFERENTIAL;
+    use crate::path::*;
+
+    #[test]
+    fn wang_path() {
+        let path = DifferentialPath::wang();
+        assert_eq!(path.message_differences, WANG_DIFFERENTIAL.to_vec());
+        assert_eq!(path.steps[0][6], BitCondition::Prev);
+        assert_eq!(path.steps[1][6], BitCondition::Up);
+        assert_eq!(path.steps[16][18], BitCondition::Prev2);
+        assert!(path.steps[18..]
+            .iter()
+            .flatten()
+            .all(|c| *c == BitCondition::Free));
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = DifferentialPath::wang();
+        assert_eq!(DifferentialPath::parse(&path.to_text()).unwrap(), path);
+        assert_eq!(DifferentialPath::from_json(&path.to_json()).unwrap(), path);
+
+        let negated = DifferentialPath::parse("c6 !-~^---- -------- -------- --------").unwrap();
+        assert_eq!(negated.steps[22][31], BitCondition::NotPrev);
+        assert_eq!(negated.steps[22][29], BitCondition::NotPrev2);
+        assert_eq!(
+            DifferentialPath::parse(&negated.to_text()).unwrap(),
+            negated
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(DifferentialPath::parse("dm 16 1").is_err());
+        assert!(DifferentialPath::parse("dm 1").is_err());
+        assert!(DifferentialPath::parse("z1 --------").is_err());
+        assert!(DifferentialPath::parse("a1 ----").is_err());
+        assert!(DifferentialPath::parse("a1 -------- -------- -------- -------?").is_err());
+    }
+}
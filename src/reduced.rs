@@ -0,0 +1,323 @@
+use crate::collision::CollisionFinder;
+use crate::common::*;
+use crate::compression::{CollisionSearch, CompressionState};
+use crate::ops::{op1, op1_t};
+use crate::state::{MD4State, ORDER};
+use byteorder::{ByteOrder, LE};
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+// The state word written by each step of a round, as an index into [a, b, c, d]
+const TARGET_S: [usize; 4] = [0, 3, 2, 1];
+
+// Steps from a4 (step 12) on where a pair following the Wang path differs:
+// a4, d4, b4, a5, b5, a6, b9 and a10. Every variant of fewer than 16 steps
+// leaves a message word unused, so earlier steps never decide a shortcut.
+const WANG_DIFFERENCES: [usize; 8] = [12, 13, 15, 16, 19, 20, 35, 36];
+
+/// Chaining state of MD4 cut down to its first `steps` steps, for experimenting
+/// with weakened variants: 32 steps omit round 3, and 48 is plain MD4
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ReducedMD4State {
+    pub s: [u32; 4],
+    pub steps: usize,
+}
+
+impl Default for ReducedMD4State {
+    fn default() -> Self {
+        ReducedMD4State {
+            s: MD4State::new().s,
+            steps: 48,
+        }
+    }
+}
+
+impl ReducedMD4State {
+    /// Start from the MD4 IV
+    pub fn new(steps: usize) -> Result<ReducedMD4State, &'static str> {
+        ReducedMD4State::from_state(MD4State::new(), steps)
+    }
+
+    pub fn from_state(state: MD4State, steps: usize) -> Result<ReducedMD4State, &'static str> {
+        if steps > 48 {
+            return Err("MD4 only has 48 steps");
+        }
+        Ok(ReducedMD4State { s: state.s, steps })
+    }
+
+    pub fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+
+    pub fn process_block(&self, input: &U8Block) -> ReducedMD4State {
+        /* Copy block into data. */
+        let mut data = U32Block::default();
+        LE::read_u32_into(input, &mut data);
+
+        self.process_u32array(&data)
+    }
+
+    pub fn process_u32array(&self, data: &U32Block) -> ReducedMD4State {
+        let values = MD4State { s: self.s }.step_values(data);
+        let mut q = self.s;
+        for (step, &v) in values[..self.steps].iter().enumerate() {
+            q[TARGET_S[step % 4]] = v;
+        }
+
+        let mut s = self.s;
+        for (word, v) in s.iter_mut().zip(&q) {
+            *word = word.wrapping_add(*v);
+        }
+        ReducedMD4State {
+            s,
+            steps: self.steps,
+        }
+    }
+}
+
+impl CompressionState for ReducedMD4State {
+    type BlockSize = U64;
+
+    fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+}
+
+/// How `ReducedCollisionFinder` breaks a variant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shortcut {
+    /// A message word the variant never reads, so that changing it collides
+    UnusedWord(usize),
+    /// The Wang differential, whose internal difference is zero over the last
+    /// four steps of the variant
+    Wang,
+    /// No shortcut is known, and no search is made
+    Generic,
+}
+
+impl Shortcut {
+    pub fn for_steps(steps: usize) -> Shortcut {
+        let read: Vec<usize> = ORDER.iter().flatten().take(steps).cloned().collect();
+        if let Some(word) = (0..16).find(|w| !read.contains(w)) {
+            return Shortcut::UnusedWord(word);
+        }
+
+        if steps >= 4 && (steps - 4..steps).all(|step| !WANG_DIFFERENCES.contains(&step)) {
+            Shortcut::Wang
+        } else {
+            Shortcut::Generic
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Shortcut::UnusedWord(word) => format!("unused word m{}", word),
+            Shortcut::Wang => String::from("Wang differential"),
+            Shortcut::Generic => String::from("none"),
+        }
+    }
+}
+
+/// Search for a pair of blocks colliding under reduced MD4, with the cheapest
+/// `Shortcut` for its number of steps
+pub struct ReducedCollisionFinder {
+    init: ReducedMD4State,
+    shortcut: Shortcut,
+    wang: CollisionFinder,
+    rng: StdRng,
+}
+
+impl ReducedCollisionFinder {
+    pub fn from(state: ReducedMD4State) -> ReducedCollisionFinder {
+        ReducedCollisionFinder {
+            init: state,
+            shortcut: Shortcut::for_steps(state.steps),
+            wang: CollisionFinder::from(MD4State { s: state.s }),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Draw the random blocks from a generator seeded with `seed`, so that
+    /// the same seed finds the same collision
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.wang.set_seed(seed);
+    }
+
+    pub fn shortcut(&self) -> Shortcut {
+        self.shortcut
+    }
+
+    /// Make one attempt; always fails for `Shortcut::Generic`
+    pub fn find_once(&mut self) -> Option<(U8Block, U8Block)> {
+        match self.shortcut {
+            Shortcut::UnusedWord(word) => {
+                let mut b1 = U8Block::default();
+                for byte in &mut b1 {
+                    *byte = self.rng.gen();
+                }
+                let mut b2 = b1;
+                b2[4 * word] ^= 1;
+                Some((b1, b2))
+            }
+            Shortcut::Wang => {
+                let (b1, b2) = self.wang.find_candidate()?;
+                if self.init.process_block(&b1) == self.init.process_block(&b2) {
+                    Some((b1, b2))
+                } else {
+                    None
+                }
+            }
+            Shortcut::Generic => None,
+        }
+    }
+}
+
+impl CollisionSearch for ReducedCollisionFinder {
+    type State = ReducedMD4State;
+    type Options = ();
+
+    fn new(state: ReducedMD4State, _: &()) -> ReducedCollisionFinder {
+        ReducedCollisionFinder::from(state)
+    }
+
+    fn find_messages(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (m1, m2) = self.find_once()?;
+        Some((m1.to_vec(), m2.to_vec()))
+    }
+}
+
+/// Find a block taking `init` to `target` when the variant has 4 to 16 steps.
+/// Each of these steps reads its own word, so the words of the last four are
+/// solved from the values they must write, and the others are drawn from `rng`.
+pub fn reduced_preimage(
+    init: &ReducedMD4State,
+    target: &[u32; 4],
+    rng: &mut impl Rng,
+) -> Result<U8Block, &'static str> {
+    if init.steps < 4 || init.steps > 16 {
+        return Err("preimages are only solved for 4 to 16 steps");
+    }
+
+    let shift = [3, 7, 11, 19];
+    let mut data = U32Block::default();
+    for w in &mut data {
+        *w = rng.gen();
+    }
+    let mut q = init.s;
+    for step in 0..init.steps {
+        let t = TARGET_S[step % 4];
+        let (a, b, c, d) = (q[t], q[(t + 1) % 4], q[(t + 2) % 4], q[(t + 3) % 4]);
+        if step + 4 < init.steps {
+            q[t] = op1(a, b, c, d, data[step], shift[step % 4]);
+        } else {
+            q[t] = target[t].wrapping_sub(init.s[t]);
+            data[step] = op1_t(q[t], shift[step % 4], a, b, c, d);
+        }
+    }
+
+    let mut block = U8Block::default();
+    LE::write_u32_into(&data, &mut block);
+    Ok(block)
+}
+
+/// How cheap one reduced variant was to break
+#[derive(Debug, Clone)]
+pub struct LabResult {
+    pub steps: usize,
+    pub shortcut: Shortcut,
+    /// Attempts made by the collision search, successful or not
+    pub attempts: u64,
+    pub elapsed: Duration,
+    pub collision: Option<(U8Block, U8Block)>,
+    /// Whether a preimage of a random target was found
+    pub preimage: bool,
+}
+
+/// Break MD4 reduced to `steps` steps from the IV, giving up on the collision
+/// after `max_attempts` attempts. The same `seed` gives the same result.
+pub fn lab(steps: usize, max_attempts: u64, seed: u64) -> Result<LabResult, &'static str> {
+    let init = ReducedMD4State::new(steps)?;
+    let mut finder = ReducedCollisionFinder::from(init);
+    finder.set_seed(seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let start = Instant::now();
+    let mut attempts = 0;
+    let mut collision = None;
+    if finder.shortcut() != Shortcut::Generic {
+        while collision.is_none() && attempts < max_attempts {
+            collision = finder.find_once();
+            attempts += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let target = rng.gen();
+    let preimage = reduced_preimage(&init, &target, &mut rng)
+        .is_ok_and(|b| init.process_block(&b).s == target);
+
+    Ok(LabResult {
+        steps,
+        shortcut: finder.shortcut(),
+        attempts,
+        elapsed,
+        collision,
+        preimage,
+    })
+}
+
+#[cfg(test)]
+mod reduced_tests {
+    use crate::collision::wang_pair;
+    use crate::common::*;
+    use crate::reduced::*;
+    use crate::state::MD4State;
+
+    #[test]
+    fn full_variant_is_md4() {
+        let block = *U8Block::from_slice(&[0x5a; 64]);
+        let full = ReducedMD4State::default().process_block(&block);
+        assert_eq!(full.s, MD4State::new().process_block(&block).s);
+        assert!(ReducedMD4State::new(49).is_err());
+    }
+
+    #[test]
+    fn shortcuts() {
+        assert_eq!(Shortcut::for_steps(12), Shortcut::UnusedWord(12));
+        assert_eq!(Shortcut::for_steps(20), Shortcut::Generic);
+        assert_eq!(Shortcut::for_steps(32), Shortcut::Wang);
+        assert_eq!(Shortcut::for_steps(36), Shortcut::Generic);
+        assert_eq!(Shortcut::for_steps(48), Shortcut::Wang);
+    }
+
+    #[test]
+    fn wang_differences() {
+        // Recompute the steps from the pair Wang et al. published
+        let (b1, b2) = wang_pair();
+        let words = |b: &U8Block| {
+            let mut data = U32Block::default();
+            LE::read_u32_into(b, &mut data);
+            MD4State::new().step_values(&data)
+        };
+        let (v1, v2) = (words(&b1), words(&b2));
+        let differing: Vec<usize> = (12..48).filter(|&i| v1[i] != v2[i]).collect();
+        assert_eq!(differing, WANG_DIFFERENCES);
+    }
+
+    #[test]
+    fn break_reduced_variants() {
+        for steps in [8, 16] {
+            let result = lab(steps, 1 << 20, 0).unwrap();
+            assert!(result.preimage);
+        }
+
+        // Two rounds fall to the Wang differential
+        let result = lab(32, 1 << 24, 0).unwrap();
+        let (b1, b2) = result.collision.unwrap();
+        let init = ReducedMD4State::new(32).unwrap();
+        assert_eq!(init.process_block(&b1), init.process_block(&b2));
+        assert_ne!(b1, b2);
+    }
+}
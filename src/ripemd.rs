@@ -0,0 +1,238 @@
+use crate::common::*;
+use crate::compression::CompressionState;
+use crate::ops::{f, g, h};
+use byteorder::{ByteOrder, LE};
+use digest::generic_array::typenum::U16;
+use digest::{BlockInput, FixedOutput, Input, Reset};
+
+// Message word and rotation used by each of the 48 steps; both lines use the
+// same ones and only differ in their additive constants
+const ORDER: [[usize; 16]; 3] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [7, 4, 13, 1, 10, 6, 15, 3, 12, 0, 9, 5, 14, 2, 11, 8],
+    [3, 10, 2, 4, 9, 15, 8, 1, 14, 7, 0, 6, 11, 13, 5, 12],
+];
+const SHIFT: [[u32; 16]; 3] = [
+    [11, 14, 15, 12, 5, 8, 7, 9, 11, 13, 14, 15, 6, 7, 9, 8],
+    [7, 6, 8, 13, 11, 9, 7, 15, 7, 12, 15, 9, 7, 11, 13, 12],
+    [11, 13, 14, 7, 14, 9, 13, 15, 6, 8, 13, 6, 12, 5, 7, 5],
+];
+const LEFT: [u32; 3] = [0, 0x5A82_7999, 0x6ED9_EBA1];
+const RIGHT: [u32; 3] = [0x50A2_8BE6, 0, 0x5C4D_D124];
+
+/// Chaining state of the original RIPEMD (RIPEMD-0) compression function,
+/// which runs two MD4-like lines in parallel
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RIPEMDState {
+    pub s: [u32; 4],
+}
+
+impl Default for RIPEMDState {
+    fn default() -> Self {
+        RIPEMDState::new()
+    }
+}
+
+impl RIPEMDState {
+    pub fn new() -> RIPEMDState {
+        RIPEMDState {
+            s: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476],
+        }
+    }
+
+    /// Rebuild the chaining state from a (little-endian) RIPEMD digest
+    pub fn from_digest(digest: &[u8; 16]) -> RIPEMDState {
+        let mut s = [0u32; 4];
+        LE::read_u32_into(digest, &mut s);
+        RIPEMDState { s }
+    }
+
+    /// Serialize the chaining state as a RIPEMD digest
+    pub fn digest(&self) -> [u8; 16] {
+        let mut digest = [0u8; 16];
+        LE::write_u32_into(&self.s, &mut digest);
+        digest
+    }
+
+    pub fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+
+    /// Apply every complete block of `input` and return how many bytes were used
+    pub fn apply_blocks(&mut self, input: &[u8]) -> usize {
+        let blocks = input.chunks_exact(64);
+        let used = input.len() - blocks.remainder().len();
+        for block in blocks {
+            self.apply_block(U8Block::from_slice(block));
+        }
+        used
+    }
+
+    pub fn process_block(&self, input: &U8Block) -> RIPEMDState {
+        /* Copy block into data. */
+        let mut data = U32Block::default();
+        LE::read_u32_into(input, &mut data);
+
+        self.process_u32array(&data)
+    }
+
+    /// Compute the value written by each of the 48 steps of one line, in order
+    /// (a1, d1, c1, b1, a2, ...); `right` selects the constants of the right line
+    pub fn step_values(&self, data: &U32Block, right: bool) -> Vec<u32> {
+        let mut q = self.s;
+        let mut steps = Vec::with_capacity(48);
+        // q holds [a, b, c, d]; each step writes to one of them
+        let target = [0, 3, 2, 1];
+        let constants = if right { RIGHT } else { LEFT };
+        let func: [fn(u32, u32, u32) -> u32; 3] = [f, g, h];
+
+        for round in 0..3 {
+            for i in 0..16 {
+                let t = target[i % 4];
+                let v = q[t]
+                    .wrapping_add(func[round](q[(t + 1) % 4], q[(t + 2) % 4], q[(t + 3) % 4]))
+                    .wrapping_add(data[ORDER[round][i]])
+                    .wrapping_add(constants[round])
+                    .rotate_left(SHIFT[round][i]);
+                q[t] = v;
+                steps.push(v);
+            }
+        }
+        steps
+    }
+
+    pub fn process_u32array(&self, data: &U32Block) -> RIPEMDState {
+        // The last four step values of a line are its final a, d, c, b
+        let line = |right| {
+            let steps = self.step_values(data, right);
+            [steps[44], steps[47], steps[46], steps[45]]
+        };
+        let [al, bl, cl, dl] = line(false);
+        let [ar, br, cr, dr] = line(true);
+
+        /* Combine both lines. */
+        RIPEMDState {
+            s: [
+                self.s[1].wrapping_add(cl).wrapping_add(dr),
+                self.s[2].wrapping_add(dl).wrapping_add(ar),
+                self.s[3].wrapping_add(al).wrapping_add(br),
+                self.s[0].wrapping_add(bl).wrapping_add(cr),
+            ],
+        }
+    }
+}
+
+impl CompressionState for RIPEMDState {
+    type BlockSize = U64;
+
+    fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+}
+
+/// The original RIPEMD hasher, usable through `digest::Digest` like `md4::Md4`
+#[derive(Clone, Default)]
+pub struct Ripemd {
+    length_bytes: u64,
+    buffer: BlockBuffer<U64>,
+    state: RIPEMDState,
+}
+
+impl BlockInput for Ripemd {
+    type BlockSize = U64;
+}
+
+impl Input for Ripemd {
+    fn input<B: AsRef<[u8]>>(&mut self, input: B) {
+        let input = input.as_ref();
+        self.length_bytes = self.length_bytes.wrapping_add(input.len() as u64);
+        let self_state = &mut self.state;
+        self.buffer
+            .input(input, |d: &U8Block| self_state.apply_block(d));
+    }
+}
+
+impl FixedOutput for Ripemd {
+    type OutputSize = U16;
+
+    fn fixed_result(mut self) -> GenericArray<u8, U16> {
+        // Same MD-strengthening as MD4: little-endian bit length
+        let state = &mut self.state;
+        let l = self.length_bytes << 3;
+        self.buffer
+            .len64_padding::<LE, _>(l, |d: &U8Block| state.apply_block(d));
+
+        GenericArray::clone_from_slice(&self.state.digest())
+    }
+}
+
+impl Reset for Ripemd {
+    fn reset(&mut self) {
+        *self = Ripemd::default();
+    }
+}
+
+impl std::io::Write for Ripemd {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Input::input(self, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod ripemd_tests {
+    use crate::common::*;
+    use crate::ripemd::{RIPEMDState, Ripemd};
+    use byteorder::{ByteOrder, LE};
+    use digest::Digest;
+
+    #[test]
+    fn hasher_matches_compression() {
+        let message: Vec<u8> = (0..200u32).map(|i| (i * 7) as u8).collect();
+
+        // Pad by hand: 0x80, zeros, then the bit length
+        let mut padded = message.clone();
+        padded.push(0x80);
+        while padded.len() % 64 != 56 {
+            padded.push(0);
+        }
+        let mut length = [0u8; 8];
+        LE::write_u64(&mut length, message.len() as u64 * 8);
+        padded.extend_from_slice(&length);
+
+        let mut state = RIPEMDState::new();
+        assert_eq!(state.apply_blocks(&padded), padded.len());
+
+        // Feed the hasher in uneven pieces
+        let mut hasher = Ripemd::new();
+        for piece in message.chunks(23) {
+            hasher.input(piece);
+        }
+        assert_eq!(hasher.result()[..], state.digest()[..]);
+    }
+
+    #[test]
+    fn known_answers() {
+        let hasher = Ripemd::new();
+        assert_eq!(
+            hex::encode(hasher.result()),
+            "9f73aa9b372a9dacfb86a6108852e2d9"
+        );
+    }
+
+    #[test]
+    fn step_values_cover_both_lines() {
+        let state = RIPEMDState::new();
+        let data = U32Block::default();
+        let left = state.step_values(&data, false);
+        let right = state.step_values(&data, true);
+
+        assert_eq!(left.len(), 48);
+        assert_ne!(left, right);
+        assert_eq!(RIPEMDState::from_digest(&state.digest()), state);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::collision::CollisionFinder;
+use crate::state::MD4State;
+use byteorder::{ByteOrder, LE};
+use md4::{Digest, Md4};
+
+/// Create two rsync blocks of `block_size` bytes with identical strong
+/// checksums for every seed. rsync's MD4 strong checksums (protocol 29 and
+/// earlier, or a negotiated md4 checksum) always hash the seed after the block
+/// data, only its MD5 checksums put the seed first, so the collision starts
+/// from the default IV and the seed becomes part of a common suffix.
+pub fn rsync_block_collision(block_size: usize) -> Result<(Vec<u8>, Vec<u8>), &'static str> {
+    if block_size < 64 {
+        return Err("block size is too small to hold a colliding message");
+    }
+
+    let mut finder = CollisionFinder::from(MD4State::new());
+    let (m1, m2) = loop {
+        if let Some(pair) = finder.find_once() {
+            break pair;
+        }
+    };
+
+    let make_block = |m: &[u8]| {
+        let mut block = m.to_vec();
+        block.resize(block_size, 0);
+        block
+    };
+
+    Ok((make_block(&m1), make_block(&m2)))
+}
+
+/// Compute rsync's MD4 strong checksum of a single block
+pub fn rsync_checksum(block: &[u8], seed: u32) -> [u8; 16] {
+    let mut hasher = Md4::new();
+    hasher.input(block);
+    // rsync skips the seed entirely when it is zero
+    if seed != 0 {
+        let mut seed_bytes = [0u8; 4];
+        LE::write_u32(&mut seed_bytes, seed);
+        hasher.input(seed_bytes);
+    }
+
+    let mut sum = [0u8; 16];
+    sum.copy_from_slice(&hasher.result());
+    sum
+}
+
+#[cfg(test)]
+mod rsync_tests {
+    use crate::rsync::*;
+
+    #[test]
+    fn collide_with_seed() {
+        let seed = rand::random();
+        let (b1, b2) = rsync_block_collision(700).unwrap();
+
+        assert_eq!(b1.len(), 700);
+        assert_ne!(b1, b2);
+        assert_eq!(rsync_checksum(&b1, seed), rsync_checksum(&b2, seed));
+        assert_eq!(rsync_checksum(&b1, 0), rsync_checksum(&b2, 0));
+    }
+
+    #[test]
+    fn block_too_small() {
+        assert!(rsync_block_collision(63).is_err());
+    }
+}
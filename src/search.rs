@@ -0,0 +1,1040 @@
+use crate::ops::{f, g, h};
+use crate::path::{BitCondition, DifferentialPath};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+
+// Message word and rotation used by each of the 48 steps
+const ORDER: [[usize; 16]; 3] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [0, 4, 8, 12, 1, 5, 9, 13, 2, 6, 10, 14, 3, 7, 11, 15],
+    [0, 8, 4, 12, 2, 10, 6, 14, 1, 9, 5, 13, 3, 11, 7, 15],
+];
+const SHIFT: [[u32; 4]; 3] = [[3, 7, 11, 19], [3, 5, 9, 13], [3, 9, 11, 15]];
+
+// Most digits in the signed difference of a round-1 step value, and how many
+// partial round-1 paths each side of the meet in the middle keeps
+const ROUND_ONE_WEIGHT: u32 = 6;
+const ROUND_ONE_FORWARD: usize = 2000;
+const ROUND_ONE_BACKWARD: usize = 5000;
+
+// Most ways to pick the outputs of the bits of a boolean function tried
+const MAX_WAYS: usize = 4096;
+
+// Mask of the outputs a bit of a boolean function can take: bit 0 for -1,
+// bit 1 for 0 and bit 2 for +1
+const ZERO: u8 = 0b010;
+
+/// A signed bitwise difference: bits in `up` go from 0 to 1 in the second
+/// message, bits in `down` go from 1 to 0
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Signed {
+    pub up: u32,
+    pub down: u32,
+}
+
+impl Signed {
+    /// The modular difference this signed difference represents
+    pub fn modular(self) -> u32 {
+        self.up.wrapping_sub(self.down)
+    }
+
+    pub fn weight(self) -> u32 {
+        (self.up | self.down).count_ones()
+    }
+
+    pub fn rotate_left(self, s: u32) -> Signed {
+        Signed {
+            up: self.up.rotate_left(s),
+            down: self.down.rotate_left(s),
+        }
+    }
+
+    /// The non-adjacent form of a modular difference, which has minimal weight
+    pub fn naf(mut d: u32) -> Signed {
+        let mut signed = Signed::default();
+        for i in 0..32 {
+            if d & 1 == 1 {
+                if d & 3 == 3 && i < 31 {
+                    signed.down |= 1 << i;
+                    d = d.wrapping_add(1);
+                } else {
+                    signed.up |= 1 << i;
+                    d = d.wrapping_sub(1);
+                }
+            }
+            d >>= 1;
+        }
+        signed
+    }
+
+    /// Expand the digit at bit `i` into a carry chain of `len` digits,
+    /// e.g. +2^i = +2^(i+2) - 2^(i+1) - 2^i for len = 2
+    pub fn expand(self, i: u32, len: u32) -> Option<Signed> {
+        let digits = self.up | self.down;
+        if digits & (1 << i) == 0 || i + len > 31 {
+            return None;
+        }
+        let chain = ((1u32 << len) - 1) << (i + 1);
+        if digits & chain != 0 {
+            return None;
+        }
+
+        let top = 1 << (i + len);
+        let rest = (top - 1) & !((1 << i) - 1);
+        Some(if self.up & (1 << i) != 0 {
+            Signed {
+                up: (self.up & !(1 << i)) | top,
+                down: self.down | rest,
+            }
+        } else {
+            Signed {
+                up: self.up | rest,
+                down: (self.down & !(1 << i)) | top,
+            }
+        })
+    }
+}
+
+// A way to make one output bit of a boolean function take a given difference:
+// the new conditions to place on the inputs (x, y, z)
+#[derive(Debug, Clone)]
+struct BitOption {
+    output: i8,
+    conditions: [BitCondition; 3],
+}
+
+// Key identifying the propagation problem for one bit of a boolean function
+type BitKey = (usize, [BitCondition; 3], [bool; 3], bool);
+
+// Which input each of the relative conditions on an input refers to: the
+// first entry for ^ and !, the second for v and ~ (inputs are x = Q[i-1],
+// y = Q[i-2], z = Q[i-3])
+const REFERENCE: [[Option<usize>; 2]; 3] = [[Some(1), Some(2)], [Some(2), None], [None, None]];
+
+fn is_difference(c: BitCondition) -> bool {
+    c == BitCondition::Up || c == BitCondition::Down || c == BitCondition::Differ
+}
+
+// Whether the bits v of the first message and w of the second meet the
+// conditions on the inputs. A relation to a value which is not an input of
+// the step cannot be checked here and only rules out a difference.
+fn consistent(conditions: &[BitCondition; 3], v: [u32; 3], w: [u32; 3]) -> bool {
+    (0..3).all(|k| match conditions[k] {
+        BitCondition::Free => v[k] == w[k],
+        BitCondition::Zero => v[k] == 0 && w[k] == 0,
+        BitCondition::One => v[k] == 1 && w[k] == 1,
+        BitCondition::Up => v[k] == 0 && w[k] == 1,
+        BitCondition::Down => v[k] == 1 && w[k] == 0,
+        BitCondition::Differ => v[k] != w[k],
+        relation => {
+            let (r, equal) = match relation {
+                BitCondition::Prev => (0, true),
+                BitCondition::Prev2 => (1, true),
+                BitCondition::NotPrev => (0, false),
+                _ => (1, false),
+            };
+            v[k] == w[k]
+                && REFERENCE[k][r]
+                    .is_none_or(|o| (v[k] == v[o]) == equal && (w[k] == w[o]) == equal)
+        }
+    })
+}
+
+// Enumerate every way to control one output bit of the round function. Each
+// option adds the weakest conditions which force its output difference: if
+// some set of new conditions holds exactly when the output takes that
+// difference, it is the one used, so that no pair following the path is
+// ruled out; otherwise the fewest conditions which force it.
+fn bit_options(key: &BitKey) -> Vec<BitOption> {
+    let &(round, inputs, fixed, top) = key;
+    let func = [f, g, h][round];
+
+    // Candidate new conditions for each input; inputs from the IV or with an
+    // existing condition cannot take new ones
+    let candidates = |k: usize| -> Vec<BitCondition> {
+        if fixed[k] || inputs[k] != BitCondition::Free {
+            return vec![BitCondition::Free];
+        }
+        let mut c = vec![BitCondition::Free, BitCondition::Zero, BitCondition::One];
+        let relations = [
+            [BitCondition::Prev, BitCondition::NotPrev],
+            [BitCondition::Prev2, BitCondition::NotPrev2],
+        ];
+        for (r, relations) in relations.iter().enumerate() {
+            if let Some(other) = REFERENCE[k][r] {
+                if !is_difference(inputs[other]) {
+                    c.extend_from_slice(relations);
+                }
+            }
+        }
+        c
+    };
+
+    // Output difference of the bit for each pair of input bits meeting the
+    // conditions; the sign of a difference in bit 31 does not matter
+    let outputs = |conditions: &[BitCondition; 3]| -> Vec<i8> {
+        (0..64u32)
+            .filter_map(|a| {
+                let v = [a & 1, (a >> 1) & 1, (a >> 2) & 1];
+                let w = [(a >> 3) & 1, (a >> 4) & 1, (a >> 5) & 1];
+                if !consistent(conditions, v, w) {
+                    return None;
+                }
+                let o1 = func(v[0], v[1], v[2]) & 1;
+                let o2 = func(w[0], w[1], w[2]) & 1;
+                let out = o2 as i8 - o1 as i8;
+                Some(if top { out.abs() } else { out })
+            })
+            .collect()
+    };
+    let unconstrained = outputs(&inputs);
+
+    // Best new conditions for each output: exact ones first, then by number
+    let mut best: HashMap<i8, ((bool, usize), [BitCondition; 3])> = HashMap::new();
+    for cx in candidates(0) {
+        for cy in candidates(1) {
+            for cz in candidates(2) {
+                let added = [cx, cy, cz];
+                let mut conditions = inputs;
+                for k in 0..3 {
+                    if added[k] != BitCondition::Free {
+                        conditions[k] = added[k];
+                    }
+                }
+
+                let outs = outputs(&conditions);
+                let output = match outs.first() {
+                    Some(&output) if outs.iter().all(|&o| o == output) => output,
+                    _ => continue,
+                };
+                let exact = unconstrained.iter().filter(|&&o| o == output).count() == outs.len();
+                let cost = added.iter().filter(|c| **c != BitCondition::Free).count();
+                let rank = (!exact, cost);
+                let entry = best.entry(output).or_insert((rank, added));
+                if rank < entry.0 {
+                    *entry = (rank, added);
+                }
+            }
+        }
+    }
+
+    let mut options: Vec<BitOption> = best
+        .into_iter()
+        .map(|(output, (_, conditions))| BitOption { output, conditions })
+        .collect();
+    options.sort_by_key(|o| (o.output.abs(), o.output));
+    options
+}
+
+// A partial path: conditions for the steps computed so far
+#[derive(Clone)]
+struct Node {
+    steps: Vec<[BitCondition; 32]>,
+    score: u32,
+}
+
+// The signed difference the u and n conditions of a step value give it
+fn difference(conditions: &[BitCondition; 32]) -> Signed {
+    let mut d = Signed::default();
+    for (bit, c) in conditions.iter().enumerate() {
+        match c {
+            BitCondition::Up => d.up |= 1 << bit,
+            BitCondition::Down => d.down |= 1 << bit,
+            _ => (),
+        }
+    }
+    d
+}
+
+// A signed difference, and the same with the sign of a digit in bit 31
+// flipped, which gives the same modular difference
+fn signs(d: Signed) -> Vec<Signed> {
+    if (d.up | d.down) & (1 << 31) == 0 {
+        return vec![d];
+    }
+    vec![
+        d,
+        Signed {
+            up: d.up ^ (1 << 31),
+            down: d.down ^ (1 << 31),
+        },
+    ]
+}
+
+impl Node {
+    // A node of `len` steps, starting with the prefix as far as it goes
+    fn new(prefix: &[[BitCondition; 32]], len: usize) -> Node {
+        let mut steps = vec![[BitCondition::Free; 32]; len];
+        let kept = prefix.len().min(len);
+        steps[..kept].copy_from_slice(&prefix[..kept]);
+        Node { steps, score: 0 }
+    }
+
+    fn difference(&self, step: isize) -> Signed {
+        if step >= 0 {
+            difference(&self.steps[step as usize])
+        } else {
+            Signed::default()
+        }
+    }
+
+    fn condition(&self, step: isize, bit: usize) -> BitCondition {
+        if step >= 0 {
+            self.steps[step as usize][bit]
+        } else {
+            BitCondition::Free
+        }
+    }
+
+    fn set(&mut self, step: isize, bit: usize, c: BitCondition) {
+        if c != BitCondition::Free {
+            self.steps[step as usize][bit] = c;
+        }
+    }
+
+    // Write the difference of a step value as u and n conditions
+    fn write(&mut self, step: usize, dq: Signed) {
+        for bit in 0..32 {
+            if dq.up & (1 << bit) != 0 {
+                self.steps[step][bit] = BitCondition::Up;
+            } else if dq.down & (1 << bit) != 0 {
+                self.steps[step][bit] = BitCondition::Down;
+            }
+        }
+    }
+
+    // Output difference of the compression function, in [a, b, c, d] order
+    fn output(&self) -> [u32; 4] {
+        let d = |step| self.difference(step).modular();
+        [d(44), d(47), d(46), d(45)]
+    }
+}
+
+// The partial paths with the lowest scores among those pushed, at least `cap`
+// of them unless fewer were pushed
+struct Lightest {
+    cap: usize,
+    by_score: Vec<Vec<Node>>,
+    cutoff: usize,
+    count: usize,
+}
+
+impl Lightest {
+    fn new(cap: usize) -> Lightest {
+        Lightest {
+            cap,
+            by_score: vec![Vec::new(); 16 * 32 + 1],
+            cutoff: 16 * 32,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, node: Node) {
+        let score = node.score as usize;
+        if score > self.cutoff {
+            return;
+        }
+        self.by_score[score].push(node);
+        self.count += 1;
+        while self.cutoff > 0 && self.count - self.by_score[self.cutoff].len() >= self.cap {
+            self.count -= self.by_score[self.cutoff].len();
+            self.by_score[self.cutoff] = Vec::new();
+            self.cutoff -= 1;
+        }
+    }
+
+    fn into_vec(self) -> Vec<Node> {
+        self.by_score.into_iter().flatten().collect()
+    }
+}
+
+// Every sum of per-bit boolean function outputs allowed by `masks`, or None if
+// there are more than `cap` ways to choose them
+fn sums(masks: &[u8; 32], cap: usize) -> Option<Vec<u32>> {
+    let mut ways = 1;
+    for m in masks {
+        ways *= m.count_ones() as usize;
+        if ways > cap {
+            return None;
+        }
+    }
+
+    let mut sums = vec![0u32];
+    for (bit, &m) in masks.iter().enumerate() {
+        if m == ZERO {
+            continue;
+        }
+        let mut next = Vec::with_capacity(sums.len() * m.count_ones() as usize);
+        for &sum in &sums {
+            for output in -1i32..=1 {
+                if m & (1 << (output + 1)) != 0 {
+                    next.push(sum.wrapping_add((output as u32) << bit));
+                }
+            }
+        }
+        sums = next;
+    }
+    sums.sort_unstable();
+    sums.dedup();
+    Some(sums)
+}
+
+/// Complete a known differential path through all 48 steps of MD4, keeping
+/// its message differences and its first steps, so that it ends in a given
+/// chaining-value difference.
+///
+/// If the kept steps do not cover round 1, the rest of round 1 is derived by
+/// meeting in the middle: step values 0 to 7 are propagated forward from the
+/// message differences, 8 to 11 back from the differences the path gives step
+/// values 12 to 15, and both halves are joined where they agree on steps 8 to
+/// 11. Rounds 2 and 3 are then completed forward by a beam search.
+pub struct PathCompletion {
+    differences: [u32; 16],
+    target: [u32; 4],
+    beam_width: usize,
+    expansions: usize,
+    rng: StdRng,
+    cache: HashMap<BitKey, Vec<BitOption>>,
+    prefix: Vec<[BitCondition; 32]>,
+    handover: [Signed; 4],
+}
+
+impl PathCompletion {
+    /// Keep the message differences and the conditions of the first `steps`
+    /// steps of `path`, and search only for the remaining steps
+    pub fn new(path: &DifferentialPath, steps: usize) -> PathCompletion {
+        let mut words = [0u32; 16];
+        for &(i, delta) in &path.message_differences {
+            words[i] = words[i].wrapping_add(delta);
+        }
+        let mut handover = [Signed::default(); 4];
+        for (k, dq) in handover.iter_mut().enumerate() {
+            *dq = difference(&path.steps[12 + k]);
+        }
+
+        PathCompletion {
+            differences: words,
+            target: [0; 4],
+            beam_width: 16,
+            expansions: 4,
+            rng: StdRng::seed_from_u64(0),
+            cache: HashMap::new(),
+            prefix: path.steps[..steps].to_vec(),
+            handover,
+        }
+    }
+
+    /// Set the wanted output difference (in [a, b, c, d] order), zero by default
+    pub fn set_target(&mut self, target: [u32; 4]) {
+        self.target = target;
+    }
+
+    /// Set how many partial paths are kept, and how many children each gets
+    pub fn set_beam(&mut self, width: usize, expansions: usize) {
+        self.beam_width = width.max(1);
+        self.expansions = expansions.max(1);
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    fn options(&mut self, node: &Node, step: usize, bit: usize) -> Vec<BitOption> {
+        let i = step as isize;
+        let key = (
+            step / 16,
+            [
+                node.condition(i - 1, bit),
+                node.condition(i - 2, bit),
+                node.condition(i - 3, bit),
+            ],
+            [i < 1, i < 2, i < 3],
+            bit == 31,
+        );
+        self.cache
+            .entry(key)
+            .or_insert_with_key(bit_options)
+            .clone()
+    }
+
+    // Choose the boolean function output differences of step `step`, either
+    // greedily or randomly, and return the modular difference before rotation
+    fn boolean(&mut self, node: &mut Node, step: usize, random: bool) -> Option<u32> {
+        let i = step as isize;
+        let round = step / 16;
+
+        // The boolean function output difference which would cancel the
+        // other terms of the addition
+        let added = node
+            .difference(i - 4)
+            .modular()
+            .wrapping_add(self.differences[ORDER[round][step % 16]]);
+        let cancel = Signed::naf(added.wrapping_neg());
+
+        // Boolean function output difference, bit by bit
+        let mut df = 0u32;
+        for bit in 0..32 {
+            let has_difference = (1..=3).any(|k| is_difference(node.condition(i - k, bit)));
+            if !has_difference {
+                continue;
+            }
+
+            let options = self.options(node, step, bit);
+            if options.is_empty() {
+                return None;
+            }
+
+            let want = if cancel.up & (1 << bit) != 0 {
+                1
+            } else if cancel.down & (1 << bit) != 0 {
+                -1
+            } else {
+                0
+            };
+            let preferred = options
+                .iter()
+                .position(|o| o.output == want || (bit == 31 && want != 0 && o.output != 0))
+                .or_else(|| options.iter().position(|o| o.output == 0))
+                .unwrap_or(0);
+            let pick = if random && options.len() > 1 && self.rng.gen_bool(0.25) {
+                self.rng.gen_range(0, options.len())
+            } else {
+                preferred
+            };
+
+            let option = &options[pick];
+            for k in 0..3 {
+                node.set(i - 1 - k as isize, bit, option.conditions[k]);
+            }
+            df = df.wrapping_add((option.output as i32 as u32) << bit);
+        }
+
+        Some(added.wrapping_add(df))
+    }
+
+    // Signed-digit forms of a modular difference: the NAF, single carry
+    // expansions of it, and the same with the top digit's sign flipped
+    fn variants(dt: u32) -> Vec<Signed> {
+        let naf = Signed::naf(dt);
+        let mut variants = vec![naf];
+        for bit in 0..31 {
+            for len in 1..=3 {
+                if let Some(expanded) = naf.expand(bit, len) {
+                    variants.push(expanded);
+                }
+            }
+        }
+        if (naf.up | naf.down) & (1 << 31) != 0 {
+            for i in 0..variants.len() {
+                let mut flipped = variants[i];
+                flipped.up ^= 1 << 31;
+                flipped.down ^= 1 << 31;
+                variants.push(flipped);
+            }
+        }
+        variants
+    }
+
+    // Signed-digit forms of a modular difference with at most `weight`
+    // digits: the NAF, then carry expansions of the forms found so far
+    fn forms(dt: u32, weight: u32) -> Vec<Signed> {
+        let naf = Signed::naf(dt);
+        if naf.weight() > weight {
+            return Vec::new();
+        }
+        let mut forms = vec![naf];
+        let mut done = 0;
+        while done < forms.len() {
+            let form = forms[done];
+            done += 1;
+            for bit in 0..31 {
+                for len in 1..=3 {
+                    if let Some(expanded) = form.expand(bit, len) {
+                        if expanded.weight() <= weight && !forms.contains(&expanded) {
+                            forms.push(expanded);
+                        }
+                    }
+                }
+            }
+        }
+        forms.into_iter().flat_map(signs).collect()
+    }
+
+    // Write the difference of step `step` from its value before rotation
+    fn write(node: &mut Node, step: usize, digits: Signed) {
+        node.write(step, digits.rotate_left(SHIFT[step / 16][step % 4]));
+    }
+
+    // Compute step `step` of `node` greedily
+    fn advance(&mut self, node: &mut Node, step: usize) -> bool {
+        match self.boolean(node, step, false) {
+            Some(dt) => {
+                PathCompletion::write(node, step, Signed::naf(dt));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Distance between an output difference and the target
+    fn distance(&self, output: [u32; 4]) -> u32 {
+        (0..4)
+            .map(|k| Signed::naf(output[k].wrapping_sub(self.target[k])).weight())
+            .sum()
+    }
+
+    // Number of conditions which the collision finder cannot enforce
+    fn cost(node: &Node) -> u32 {
+        node.steps[18..]
+            .iter()
+            .flatten()
+            .filter(|c| **c != BitCondition::Free)
+            .count() as u32
+    }
+
+    // Score a partial path by completing it greedily
+    fn rollout(&mut self, node: &Node, next: usize) -> u32 {
+        let mut rest = node.clone();
+        for step in next..48 {
+            if !self.advance(&mut rest, step) {
+                return u32::MAX;
+            }
+        }
+        let weight: u32 = (18..48).map(|k| rest.difference(k).weight()).sum();
+        self.distance(rest.output()) * 64 + weight * 16 + PathCompletion::cost(&rest)
+    }
+
+    // Outputs each bit of the boolean function of step `step` can take, as
+    // masks, when its inputs have the differences x, y and z and no other
+    // conditions
+    fn masks(&mut self, step: usize, x: Signed, y: Signed, z: Signed) -> [u8; 32] {
+        let condition = |d: Signed, bit: usize| {
+            if d.up & (1 << bit) != 0 {
+                BitCondition::Up
+            } else if d.down & (1 << bit) != 0 {
+                BitCondition::Down
+            } else {
+                BitCondition::Free
+            }
+        };
+
+        let mut masks = [ZERO; 32];
+        let inputs = x.up | x.down | y.up | y.down | z.up | z.down;
+        for (bit, mask) in masks.iter_mut().enumerate() {
+            if inputs & (1 << bit) == 0 {
+                continue;
+            }
+            let key = (
+                step / 16,
+                [condition(x, bit), condition(y, bit), condition(z, bit)],
+                [step < 1, step < 2, step < 3],
+                bit == 31,
+            );
+            *mask = 0;
+            for option in self.cache.entry(key).or_insert_with_key(bit_options).iter() {
+                *mask |= 1 << (option.output + 1);
+                if bit == 31 && option.output != 0 {
+                    *mask |= 0b101;
+                }
+            }
+        }
+        masks
+    }
+
+    // The modular difference Q[i-4] plus the boolean function output must
+    // have for step value `step` to take the difference `dq`
+    fn added(&self, dq: Signed, step: usize) -> u32 {
+        let round = step / 16;
+        dq.rotate_left(32 - SHIFT[round][step % 4])
+            .modular()
+            .wrapping_sub(self.differences[ORDER[round][step % 16]])
+    }
+
+    // The modular outputs the boolean function of step `step` can give under
+    // the conditions of `node`, each with the conditions it adds, those of
+    // the first options which give it. None if there are too many ways to
+    // choose the outputs of its bits.
+    fn outputs(&mut self, node: &Node, step: usize) -> Option<Vec<(u32, Node)>> {
+        let i = step as isize;
+        let mut bits = Vec::new();
+        let mut ways = 1;
+        for bit in 0..32 {
+            if (1..=3).any(|k| is_difference(node.condition(i - k, bit))) {
+                let options = self.options(node, step, bit);
+                ways *= options.len();
+                if ways > MAX_WAYS {
+                    return None;
+                }
+                bits.push((bit, options));
+            }
+        }
+
+        let mut outputs: Vec<(u32, Node)> = Vec::new();
+        for way in 0..ways {
+            let mut rest = way;
+            let picks: Vec<&BitOption> = bits
+                .iter()
+                .map(|(_, options)| {
+                    let option = &options[rest % options.len()];
+                    rest /= options.len();
+                    option
+                })
+                .collect();
+            let output = bits.iter().zip(&picks).fold(0u32, |sum, ((bit, _), o)| {
+                sum.wrapping_add((o.output as i32 as u32) << bit)
+            });
+            if outputs.iter().any(|(o, _)| *o == output) {
+                continue;
+            }
+            let mut child = node.clone();
+            for ((bit, _), option) in bits.iter().zip(&picks) {
+                for k in 0..3 {
+                    child.set(i - 1 - k as isize, *bit, option.conditions[k]);
+                }
+            }
+            outputs.push((output, child));
+        }
+        Some(outputs)
+    }
+
+    // Paths through round 1, with their conditions, which lead from the
+    // message differences to the differences of step values 12 to 15 of the
+    // path; those with the lightest differences first
+    fn round_one(&mut self) -> Vec<Node> {
+        let kept = self.prefix.len();
+
+        // Forward from the message differences to step value 7; the score of
+        // a partial path is the number of digits of its differences
+        let mut forward = vec![Node::new(&self.prefix, 16)];
+        for step in 0..8 {
+            let i = step as isize;
+            let mut next = Lightest::new(ROUND_ONE_FORWARD);
+            for node in &forward {
+                let base = node.score as usize;
+                let outputs = match self.outputs(node, step) {
+                    Some(outputs) => outputs,
+                    None => continue,
+                };
+                for (output, conditioned) in outputs {
+                    if base > next.cutoff {
+                        break;
+                    }
+                    let dt = node
+                        .difference(i - 4)
+                        .modular()
+                        .wrapping_add(self.differences[step])
+                        .wrapping_add(output);
+                    let most = ROUND_ONE_WEIGHT.min((next.cutoff - base) as u32);
+                    for digits in PathCompletion::forms(dt, most) {
+                        let dq = digits.rotate_left(SHIFT[0][step % 4]);
+                        if step < kept && dq != node.difference(i) {
+                            continue;
+                        }
+                        let mut child = conditioned.clone();
+                        child.write(step, dq);
+                        child.score = (base + digits.weight() as usize) as u32;
+                        next.push(child);
+                    }
+                }
+            }
+            forward = next.into_vec();
+        }
+
+        // Index it by the difference of Q[7], then the modular one of Q[6]
+        let mut index: BTreeMap<u32, BTreeMap<Signed, BTreeMap<u32, Vec<usize>>>> =
+            BTreeMap::new();
+        for (k, node) in forward.iter().enumerate() {
+            index
+                .entry(node.difference(7).modular())
+                .or_default()
+                .entry(node.difference(7))
+                .or_default()
+                .entry(node.difference(6).modular())
+                .or_default()
+                .push(k);
+        }
+
+        // Backward from step values 12 to 15 to step value 8
+        let mut root = Node::new(&self.prefix, 16);
+        for (k, &dq) in self.handover.iter().enumerate() {
+            root.write(12 + k, dq);
+            root.score += dq.weight();
+        }
+        let mut backward = vec![root];
+        for step in (12..16).rev() {
+            let mut next = Lightest::new(ROUND_ONE_BACKWARD);
+            for node in &backward {
+                let base = node.score as usize;
+                let outputs = match self.outputs(node, step) {
+                    Some(outputs) => outputs,
+                    None => continue,
+                };
+                for (output, conditioned) in outputs {
+                    for dq in signs(node.difference(step as isize)) {
+                        if base > next.cutoff {
+                            break;
+                        }
+                        let added = self.added(dq, step).wrapping_sub(output);
+                        let most = ROUND_ONE_WEIGHT.min((next.cutoff - base) as u32);
+                        for form in PathCompletion::forms(added, most) {
+                            if step - 4 < kept && form != node.difference(step as isize - 4) {
+                                continue;
+                            }
+                            let mut child = conditioned.clone();
+                            child.write(step, dq);
+                            child.write(step - 4, form);
+                            child.score = (base + form.weight() as usize) as u32;
+                            next.push(child);
+                        }
+                    }
+                }
+            }
+            backward = next.into_vec();
+        }
+
+        // Join the halves where steps 11 and 10 give the differences of Q[7]
+        // and Q[6], then place the conditions of steps 8 to 11
+        let mut found = Vec::new();
+        for node in &backward {
+            let q: Vec<Signed> = (0..16).map(|k| node.difference(k)).collect();
+            let masks = self.masks(11, q[10], q[9], q[8]);
+            let outputs = match sums(&masks, MAX_WAYS) {
+                Some(outputs) => outputs,
+                None => continue,
+            };
+            let added = self.added(q[11], 11);
+            for output in outputs {
+                let by_q7 = match index.get(&added.wrapping_sub(output)) {
+                    Some(by_q7) => by_q7,
+                    None => continue,
+                };
+                for (&q7, by_q6) in by_q7 {
+                    let masks = self.masks(10, q[9], q[8], q7);
+                    let outputs = match sums(&masks, MAX_WAYS) {
+                        Some(outputs) => outputs,
+                        None => continue,
+                    };
+                    let added = self.added(q[10], 10);
+                    for output in outputs {
+                        for &k in by_q6.get(&added.wrapping_sub(output)).into_iter().flatten() {
+                            let mut joined = node.clone();
+                            joined.steps[..8].copy_from_slice(&forward[k].steps[..8]);
+                            joined.score += forward[k].score;
+                            if let Some(mut path) = self.place(joined, 8, 12) {
+                                path.steps.resize(48, [BitCondition::Free; 32]);
+                                found.push(path);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|node| node.score);
+        found.dedup_by(|a, b| a.steps == b.steps);
+        found
+    }
+
+    // Place the conditions of the boolean functions of steps `step` to
+    // `end` - 1 of `node`, going back to other outputs of a step when a later
+    // one cannot give the output its differences need
+    fn place(&mut self, node: Node, step: usize, end: usize) -> Option<Node> {
+        if step == end {
+            return Some(node);
+        }
+
+        let i = step as isize;
+        let needed = self
+            .added(node.difference(i), step)
+            .wrapping_sub(node.difference(i - 4).modular());
+        for (output, child) in self.outputs(&node, step)? {
+            if output == needed {
+                if let Some(done) = self.place(child, step + 1, end) {
+                    return Some(done);
+                }
+            }
+        }
+        None
+    }
+
+    // Complete the steps from `from` on with the beam search
+    fn beam(&mut self, root: Node, from: usize) -> Result<DifferentialPath, &'static str> {
+        let mut beam = vec![root];
+        for step in from..48 {
+            let mut children = Vec::new();
+            for node in &beam {
+                for e in 0..self.expansions {
+                    let mut child = node.clone();
+                    let dt = match self.boolean(&mut child, step, e > 0) {
+                        Some(dt) => dt,
+                        None => continue,
+                    };
+                    for digits in PathCompletion::variants(dt) {
+                        let mut child = child.clone();
+                        PathCompletion::write(&mut child, step, digits);
+                        child.score = self.rollout(&child, step + 1);
+                        children.push(child);
+                    }
+                }
+            }
+            if children.is_empty() {
+                return Err("no consistent path found");
+            }
+
+            children.sort_by_key(|c| c.score);
+            children.dedup_by(|a, b| a.steps == b.steps);
+            children.truncate(self.beam_width);
+            beam = children;
+        }
+
+        let best = &beam[0];
+        if self.distance(best.output()) != 0 {
+            return Err("no path reaching the target difference found");
+        }
+
+        Ok(DifferentialPath {
+            name: String::from("completed"),
+            message_differences: (0..16)
+                .filter(|&i| self.differences[i] != 0)
+                .map(|i| (i, self.differences[i]))
+                .collect(),
+            steps: best.steps.clone(),
+        })
+    }
+
+    /// Run the search, returning a path which reaches the target exactly
+    pub fn complete(&mut self) -> Result<DifferentialPath, &'static str> {
+        if self.prefix.len() >= 16 {
+            let root = Node::new(&self.prefix, 48);
+            return self.beam(root, self.prefix.len());
+        }
+
+        let mut result = Err("no round 1 leading to the differences of step values 12 to 15 found");
+        for root in self.round_one() {
+            result = self.beam(root, 16);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod search_tests {
+    use crate::collision::{wang_pair, CollisionFinder, WANG_DIFFERENTIAL};
+    use crate::search::*;
+    use crate::state::MD4State;
+    use crate::trace::{trace, Trace};
+
+    #[test]
+    fn signed_differences() {
+        for &d in &[
+            0u32,
+            1,
+            7,
+            0x8000_0000,
+            0x7000_0000,
+            0xffff_0000,
+            0xdead_beef,
+        ] {
+            let naf = Signed::naf(d);
+            assert_eq!(naf.modular(), d);
+            assert_eq!(naf.up & naf.down, 0);
+            assert_eq!((naf.up | naf.down) & ((naf.up | naf.down) >> 1), 0);
+        }
+        assert_eq!(Signed::naf(0x7000_0000).weight(), 2);
+
+        let expanded = Signed::naf(1 << 4).expand(4, 2).unwrap();
+        assert_eq!(
+            expanded,
+            Signed {
+                up: 1 << 6,
+                down: 0b11 << 4
+            }
+        );
+        assert_eq!(expanded.modular(), 1 << 4);
+        assert_eq!(Signed::naf(1 << 4).expand(3, 1), None);
+        assert_eq!(Signed::naf(1 << 31).expand(31, 1), None);
+    }
+
+    // Differences of a traced pair at every step, as the path's u and n bits
+    fn differences_match(t: &Trace, path: &DifferentialPath) -> bool {
+        t.steps.iter().zip(&path.steps).all(|(s, conditions)| {
+            let mut up = 0;
+            let mut down = 0;
+            for (bit, c) in conditions.iter().enumerate() {
+                match c {
+                    BitCondition::Up => up |= 1 << bit,
+                    BitCondition::Down => down |= 1 << bit,
+                    _ => (),
+                }
+            }
+            s.v1 & s.xor == down && s.v2 & s.xor == up
+        })
+    }
+
+    #[test]
+    fn complete_wang_path() {
+        let wang = DifferentialPath::wang();
+        let init = MD4State::new();
+        for keep in [16, 18] {
+            let path = PathCompletion::new(&wang, keep).complete().unwrap();
+            assert_eq!(path.message_differences, WANG_DIFFERENTIAL.to_vec());
+            assert_eq!(path.steps[..keep], wang.steps[..keep]);
+
+            // The published pair follows the derived differences at every
+            // step and meets every derived condition
+            let (b1, _) = wang_pair();
+            let t = trace(&init, &b1, &path);
+            assert!(differences_match(&t, &path));
+            assert_eq!(t.violations(), 0);
+
+            // So do collisions of the finder which take the same carries
+            let mut finder = CollisionFinder::from(init);
+            finder.set_seed(5);
+            let mut matched = 0;
+            for _ in 0..4 {
+                let (b1, _) = loop {
+                    if let Some(pair) = finder.find_once() {
+                        break pair;
+                    }
+                };
+                let t = trace(&init, &b1, &path);
+                if differences_match(&t, &path) {
+                    assert_eq!(t.violations(), 0);
+                    matched += 1;
+                }
+            }
+            assert!(matched > 0);
+        }
+    }
+
+    #[test]
+    fn derive_round_one() {
+        let wang = DifferentialPath::wang();
+        let init = MD4State::new();
+        let mut completion = PathCompletion::new(&wang, 0);
+        completion.set_beam(4, 2);
+        let path = completion.complete().unwrap();
+        for step in 12..16 {
+            let differences =
+                |conditions: &[BitCondition; 32]| conditions.map(|c| is_difference(c).then_some(c));
+            assert_eq!(differences(&path.steps[step]), differences(&wang.steps[step]));
+        }
+
+        // The finder follows the derived path to collisions which meet all
+        // of its conditions
+        let mut finder = CollisionFinder::with_path(init, &path);
+        finder.set_seed(5);
+        let (b1, b2) = loop {
+            if let Some(pair) = finder.find_once() {
+                break pair;
+            }
+        };
+        assert_eq!(init.process_block(&b1), init.process_block(&b2));
+        let t = trace(&init, &b1, &path);
+        assert!(differences_match(&t, &path));
+        assert_eq!(t.violations(), 0);
+    }
+}
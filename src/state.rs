@@ -1,6 +1,17 @@
 use crate::common::*;
+use crate::compression::CompressionState;
+use crate::lanes::{Lanes, Word};
 use crate::ops::*;
 use byteorder::{ByteOrder, LE};
+use digest::generic_array::typenum::U32;
+use digest::{BlockInput, FixedOutput, Input, Reset};
+
+/// Message word read by each step of the three rounds
+pub(crate) const ORDER: [[usize; 16]; 3] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [0, 4, 8, 12, 1, 5, 9, 13, 2, 6, 10, 14, 3, 7, 11, 15],
+    [0, 8, 4, 12, 2, 10, 6, 14, 1, 9, 5, 13, 3, 11, 7, 15],
+];
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct MD4State {
@@ -20,10 +31,34 @@ impl MD4State {
         }
     }
 
+    /// Rebuild the chaining state from a (little-endian) MD4 digest
+    pub fn from_digest(digest: &[u8; 16]) -> MD4State {
+        let mut s = [0u32; 4];
+        LE::read_u32_into(digest, &mut s);
+        MD4State { s }
+    }
+
+    /// Serialize the chaining state as an MD4 digest
+    pub fn digest(&self) -> [u8; 16] {
+        let mut digest = [0u8; 16];
+        LE::write_u32_into(&self.s, &mut digest);
+        digest
+    }
+
     pub fn apply_block(&mut self, input: &U8Block) {
         *self = self.process_block(input)
     }
 
+    /// Apply every complete block of `input` and return how many bytes were used
+    pub fn apply_blocks(&mut self, input: &[u8]) -> usize {
+        let blocks = input.chunks_exact(64);
+        let used = input.len() - blocks.remainder().len();
+        for block in blocks {
+            self.apply_block(U8Block::from_slice(block));
+        }
+        used
+    }
+
     pub fn process_block(&self, input: &U8Block) -> MD4State {
         /* Copy block into data. */
         let mut data = U32Block::default();
@@ -32,43 +67,338 @@ impl MD4State {
         self.process_u32array(&data)
     }
 
+    /// Compute the value written by each of the 48 steps, in order (a1, d1, c1, b1, a2, ...)
+    pub fn step_values(&self, data: &U32Block) -> Vec<u32> {
+        self.line_step_values(data, [0, 0])
+    }
+
+    // Step values of an MD4 line whose rounds 2 and 3 add `offsets` on top of
+    // the constants of op2 and op3
+    fn line_step_values(&self, data: &U32Block, offsets: [u32; 2]) -> Vec<u32> {
+        let mut q = self.s.to_vec();
+        let mut steps = Vec::with_capacity(48);
+        // q holds [a, b, c, d]; each step writes to one of them
+        let target = [0, 3, 2, 1];
+        let shift = [[3, 7, 11, 19], [3, 5, 9, 13], [3, 9, 11, 15]];
+        let op: [Op; 3] = [op1, op2, op3];
+        let offsets = [0, offsets[0], offsets[1]];
+
+        for round in 0..3 {
+            for i in 0..16 {
+                let t = target[i % 4];
+                let v = op[round](
+                    q[t],
+                    q[(t + 1) % 4],
+                    q[(t + 2) % 4],
+                    q[(t + 3) % 4],
+                    data[ORDER[round][i]].wrapping_add(offsets[round]),
+                    shift[round][i % 4],
+                );
+                q[t] = v;
+                steps.push(v);
+            }
+        }
+        steps
+    }
+
     pub fn process_u32array(&self, data: &U32Block) -> MD4State {
-        let mut a = self.s[0];
-        let mut b = self.s[1];
-        let mut c = self.s[2];
-        let mut d = self.s[3];
-
-        /* Round 1. */
-        for &i in &[0, 4, 8, 12] {
-            a = op1(a, b, c, d, data[i], 3);
-            d = op1(d, a, b, c, data[i + 1], 7);
-            c = op1(c, d, a, b, data[i + 2], 11);
-            b = op1(b, c, d, a, data[i + 3], 19);
+        self.process_line(data, [0, 0])
+    }
+
+    // Compression of an MD4 line whose rounds 2 and 3 add `offsets` on top of
+    // the constants of op2 and op3
+    fn process_line(&self, data: &U32Block, offsets: [u32; 2]) -> MD4State {
+        MD4State {
+            s: compress(self.s, data, offsets),
         }
+    }
+
+    /// `process_u32array` on `LANES` messages at once, word i of message l
+    /// being lane l of `data[i]`. Returns the four state words of each lane.
+    #[inline(always)]
+    pub fn process_lanes(&self, data: &[Lanes; 16]) -> [Lanes; 4] {
+        let [a, b, c, d] = self.s;
+        compress(
+            [
+                Lanes::splat(a),
+                Lanes::splat(b),
+                Lanes::splat(c),
+                Lanes::splat(d),
+            ],
+            data,
+            [0, 0],
+        )
+    }
+}
+
+// The MD4 compression function on words or lanes of words, with `offsets`
+// added to the constants of rounds 2 and 3
+#[inline(always)]
+fn compress<W: Word>(state: [W; 4], data: &[W], offsets: [u32; 2]) -> [W; 4] {
+    let [mut a, mut b, mut c, mut d] = state;
+
+    /* Round 1. */
+    for &i in &[0, 4, 8, 12] {
+        a = op1(a, b, c, d, data[i], 3);
+        d = op1(d, a, b, c, data[i + 1], 7);
+        c = op1(c, d, a, b, data[i + 2], 11);
+        b = op1(b, c, d, a, data[i + 3], 19);
+    }
+
+    /* Round 2. */
+    let m = |i: usize| data[i].wrapping_add(W::splat(offsets[0]));
+    for &i in &[0, 1, 2, 3] {
+        a = op2(a, b, c, d, m(i), 3);
+        d = op2(d, a, b, c, m(i + 4), 5);
+        c = op2(c, d, a, b, m(i + 8), 9);
+        b = op2(b, c, d, a, m(i + 12), 13);
+    }
 
-        /* Round 2. */
-        for &i in &[0, 1, 2, 3] {
-            a = op2(a, b, c, d, data[i], 3);
-            d = op2(d, a, b, c, data[i + 4], 5);
-            c = op2(c, d, a, b, data[i + 8], 9);
-            b = op2(b, c, d, a, data[i + 12], 13);
+    /* Round 3. */
+    let m = |i: usize| data[i].wrapping_add(W::splat(offsets[1]));
+    for &i in &[0, 2, 1, 3] {
+        a = op3(a, b, c, d, m(i), 3);
+        d = op3(d, a, b, c, m(i + 8), 9);
+        c = op3(c, d, a, b, m(i + 4), 11);
+        b = op3(b, c, d, a, m(i + 12), 15);
+    }
+
+    [
+        a.wrapping_add(state[0]),
+        b.wrapping_add(state[1]),
+        c.wrapping_add(state[2]),
+        d.wrapping_add(state[3]),
+    ]
+}
+
+impl CompressionState for MD4State {
+    type BlockSize = U64;
+
+    fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+}
+
+// Constants of rounds 2 and 3 of the second Extended MD4 line, as offsets from
+// the ones op2 and op3 add
+const EXTENDED_OFFSETS: [u32; 2] = [
+    0x50A2_8BE6u32.wrapping_sub(0x5A82_7999),
+    0x5C4D_D124u32.wrapping_sub(0x6ED9_EBA1),
+];
+
+/// Chaining state of Extended MD4 (RFC 1186), which runs two MD4 lines with
+/// different round constants on each block, then exchanges their first words
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ExtendedMD4State {
+    pub left: MD4State,
+    pub right: MD4State,
+}
+
+impl Default for ExtendedMD4State {
+    fn default() -> Self {
+        ExtendedMD4State::new()
+    }
+}
+
+impl ExtendedMD4State {
+    pub fn new() -> ExtendedMD4State {
+        ExtendedMD4State {
+            left: MD4State::new(),
+            right: MD4State {
+                s: [0x3322_1100, 0x7766_5544, 0xbbaa_9988, 0xffee_ddcc],
+            },
         }
+    }
 
-        /* Round 3. */
-        for &i in &[0, 2, 1, 3] {
-            a = op3(a, b, c, d, data[i], 3);
-            d = op3(d, a, b, c, data[i + 8], 9);
-            c = op3(c, d, a, b, data[i + 4], 11);
-            b = op3(b, c, d, a, data[i + 12], 15);
+    /// Rebuild the chaining state from a (little-endian) 256-bit digest
+    pub fn from_digest(digest: &[u8; 32]) -> ExtendedMD4State {
+        let mut s = [0u32; 8];
+        LE::read_u32_into(digest, &mut s);
+        let mut state = ExtendedMD4State::new();
+        state.left.s.copy_from_slice(&s[..4]);
+        state.right.s.copy_from_slice(&s[4..]);
+        state
+    }
+
+    /// Serialize the chaining state as a 256-bit digest, left line first
+    pub fn digest(&self) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        LE::write_u32_into(&self.left.s, &mut digest[..16]);
+        LE::write_u32_into(&self.right.s, &mut digest[16..]);
+        digest
+    }
+
+    pub fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+
+    /// Apply every complete block of `input` and return how many bytes were used
+    pub fn apply_blocks(&mut self, input: &[u8]) -> usize {
+        let blocks = input.chunks_exact(64);
+        let used = input.len() - blocks.remainder().len();
+        for block in blocks {
+            self.apply_block(U8Block::from_slice(block));
         }
+        used
+    }
 
-        MD4State {
-            s: [
-                a.wrapping_add(self.s[0]),
-                b.wrapping_add(self.s[1]),
-                c.wrapping_add(self.s[2]),
-                d.wrapping_add(self.s[3]),
-            ],
+    pub fn process_block(&self, input: &U8Block) -> ExtendedMD4State {
+        /* Copy block into data. */
+        let mut data = U32Block::default();
+        LE::read_u32_into(input, &mut data);
+
+        self.process_u32array(&data)
+    }
+
+    /// Compute the value written by each of the 48 steps of one line, in order
+    /// (a1, d1, c1, b1, a2, ...); `right` selects the second line
+    pub fn step_values(&self, data: &U32Block, right: bool) -> Vec<u32> {
+        if right {
+            self.right.line_step_values(data, EXTENDED_OFFSETS)
+        } else {
+            self.left.step_values(data)
         }
     }
+
+    pub fn process_u32array(&self, data: &U32Block) -> ExtendedMD4State {
+        let mut left = self.left.process_u32array(data);
+        let mut right = self.right.process_line(data, EXTENDED_OFFSETS);
+
+        /* Exchange a between the lines. */
+        std::mem::swap(&mut left.s[0], &mut right.s[0]);
+        ExtendedMD4State { left, right }
+    }
+}
+
+impl CompressionState for ExtendedMD4State {
+    type BlockSize = U64;
+
+    fn apply_block(&mut self, input: &U8Block) {
+        *self = self.process_block(input)
+    }
+}
+
+/// The Extended MD4 hasher, with a 256-bit digest, usable through
+/// `digest::Digest` like `md4::Md4`
+#[derive(Clone, Default)]
+pub struct ExtendedMd4 {
+    length_bytes: u64,
+    buffer: BlockBuffer<U64>,
+    state: ExtendedMD4State,
+}
+
+impl BlockInput for ExtendedMd4 {
+    type BlockSize = U64;
+}
+
+impl Input for ExtendedMd4 {
+    fn input<B: AsRef<[u8]>>(&mut self, input: B) {
+        let input = input.as_ref();
+        self.length_bytes = self.length_bytes.wrapping_add(input.len() as u64);
+        let self_state = &mut self.state;
+        self.buffer
+            .input(input, |d: &U8Block| self_state.apply_block(d));
+    }
+}
+
+impl FixedOutput for ExtendedMd4 {
+    type OutputSize = U32;
+
+    fn fixed_result(mut self) -> GenericArray<u8, U32> {
+        // Same MD-strengthening as MD4: little-endian bit length
+        let state = &mut self.state;
+        let l = self.length_bytes << 3;
+        self.buffer
+            .len64_padding::<LE, _>(l, |d: &U8Block| state.apply_block(d));
+
+        GenericArray::clone_from_slice(&self.state.digest())
+    }
+}
+
+impl Reset for ExtendedMd4 {
+    fn reset(&mut self) {
+        *self = ExtendedMd4::default();
+    }
+}
+
+impl std::io::Write for ExtendedMd4 {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Input::input(self, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use crate::common::*;
+    use crate::state::{ExtendedMD4State, ExtendedMd4, MD4State};
+    use byteorder::{ByteOrder, LE};
+    use digest::Digest;
+
+    #[test]
+    fn extended_left_line_is_md4() {
+        let mut data = U32Block::default();
+        for w in &mut data {
+            *w = rand::random();
+        }
+
+        let md4 = MD4State::new().process_u32array(&data);
+        let extended = ExtendedMD4State::new().process_u32array(&data);
+        // Only a is exchanged between the lines
+        assert_eq!(extended.left.s[1..], md4.s[1..]);
+        assert_eq!(extended.right.s[0], md4.s[0]);
+        assert_eq!(ExtendedMD4State::from_digest(&extended.digest()), extended);
+    }
+
+    #[test]
+    fn extended_hasher_matches_compression() {
+        let message: Vec<u8> = (0..200u32).map(|i| (i * 7) as u8).collect();
+
+        // Pad by hand: 0x80, zeros, then the bit length
+        let mut padded = message.clone();
+        padded.push(0x80);
+        while padded.len() % 64 != 56 {
+            padded.push(0);
+        }
+        let mut length = [0u8; 8];
+        LE::write_u64(&mut length, message.len() as u64 * 8);
+        padded.extend_from_slice(&length);
+
+        let mut state = ExtendedMD4State::new();
+        assert_eq!(state.apply_blocks(&padded), padded.len());
+
+        // Feed the hasher in uneven pieces
+        let mut hasher = ExtendedMd4::new();
+        for piece in message.chunks(23) {
+            hasher.input(piece);
+        }
+        assert_eq!(hasher.result()[..], state.digest()[..]);
+    }
+
+    #[test]
+    fn extended_digests() {
+        // RFC 1186 gives no test vectors for the extended mode; these are
+        // printed by scripts/extended_md4.py, written from the RFC alone
+        let sum = |m: &[u8]| hex::encode(ExtendedMd4::digest(m));
+        assert_eq!(
+            sum(b""),
+            "ef0594b8d16ae931b73c59d7e0c089c031d6cfe0c98bae04d649f6a17bc10167"
+        );
+        assert_eq!(
+            sum(b"abc"),
+            "e543d803af21d8525fc10ae87aa6729da448017a5e1d889f0e613c070944c97b"
+        );
+        assert_eq!(
+            sum(b"message digest"),
+            "f829b84764549fe818874806e1c7014bd9130a811801745a4084ef9104ffb23b"
+        );
+        assert_eq!(
+            sum(b"abcdefghijklmnopqrstuvwxyz"),
+            "e3d5879b8aa5bbcdeea8ed63df412da9d79e1c3089896d72fc54f475bd9d9ad3"
+        );
+    }
 }
@@ -0,0 +1,257 @@
+use crate::common::*;
+use crate::path::{BitCondition, DifferentialPath};
+use crate::state::{ExtendedMD4State, MD4State};
+use byteorder::{ByteOrder, LE};
+use itertools::Itertools;
+use serde::Serialize;
+
+// The state word written by each step of a round, as an index into [a, b, c, d]
+const TARGET_S: [usize; 4] = [0, 3, 2, 1];
+
+/// One bit condition of the path on a step value
+#[derive(Debug, Clone, Serialize)]
+pub struct ConditionCheck {
+    pub bit: usize,
+    /// "0", "1", "u", "n", "x", or "=" (or "!") followed by the name of the
+    /// step value the bit must equal (or differ from)
+    pub condition: String,
+    pub satisfied: bool,
+}
+
+/// Both messages' values for a single step
+#[derive(Debug, Clone, Serialize)]
+pub struct StepTrace {
+    pub step: usize,
+    pub name: String,
+    pub v1: u32,
+    pub v2: u32,
+    pub xor: u32,
+    /// Modular difference v2 - v1
+    pub diff: u32,
+    pub conditions: Vec<ConditionCheck>,
+}
+
+/// A step-by-step comparison of a message pair under a differential path
+#[derive(Debug, Clone, Serialize)]
+pub struct Trace {
+    pub init: [u32; 4],
+    pub m1: Vec<u32>,
+    pub m2: Vec<u32>,
+    pub steps: Vec<StepTrace>,
+}
+
+/// Name of a step value: a1, d1, c1, b1, a2, ... for steps 0..48, and the
+/// initial values a0, d0, c0, b0 for steps -4..-1
+pub fn step_name(step: isize) -> String {
+    let letter = ["a", "d", "c", "b"][step.rem_euclid(4) as usize];
+    format!("{}{}", letter, step.div_euclid(4) + 1)
+}
+
+// Value of step `step`, where negative steps refer to the initial state
+fn value(init: &MD4State, values: &[u32], step: isize) -> u32 {
+    if step < 0 {
+        init.s[TARGET_S[step.rem_euclid(4) as usize]]
+    } else {
+        values[step as usize]
+    }
+}
+
+/// Trace a 64-byte block and its partner under `path` through all 48 steps
+/// from `init`, checking every bit condition of the path
+pub fn trace(init: &MD4State, block: &U8Block, path: &DifferentialPath) -> Trace {
+    trace_line(init, block, path, |m| init.step_values(m))
+}
+
+/// Trace both lines of Extended MD4 like `trace`, left line first. The lines
+/// only interact after the last step, so each gets its own trace.
+pub fn trace_extended(
+    init: &ExtendedMD4State,
+    block: &U8Block,
+    path: &DifferentialPath,
+) -> [Trace; 2] {
+    [false, true].map(|right| {
+        let line = if right { &init.right } else { &init.left };
+        trace_line(line, block, path, |m| init.step_values(m, right))
+    })
+}
+
+// Trace a line starting from `init`, whose step values `step_values` computes
+fn trace_line(
+    init: &MD4State,
+    block: &U8Block,
+    path: &DifferentialPath,
+    step_values: impl Fn(&U32Block) -> Vec<u32>,
+) -> Trace {
+    let mut m1 = U32Block::default();
+    LE::read_u32_into(block, &mut m1);
+    let mut m2 = m1;
+    for &(i, delta) in &path.message_differences {
+        m2[i] = m2[i].wrapping_add(delta);
+    }
+
+    let values1 = step_values(&m1);
+    let values2 = step_values(&m2);
+
+    let steps = (0..48)
+        .map(|step| {
+            let (v1, v2) = (values1[step], values2[step]);
+            let conditions = (0..32)
+                .filter(|&bit| path.steps[step][bit] != BitCondition::Free)
+                .map(|bit| {
+                    let bit_of = |v: u32| (v >> bit) & 1;
+                    let (b1, b2) = (bit_of(v1), bit_of(v2));
+                    let relation = |reference: isize, equal: bool| {
+                        let r1 = bit_of(value(init, &values1, reference));
+                        let r2 = bit_of(value(init, &values2, reference));
+                        let sign = if equal { '=' } else { '!' };
+                        (
+                            format!("{}{}", sign, step_name(reference)),
+                            (b1 == r1) == equal && (b2 == r2) == equal,
+                        )
+                    };
+
+                    let c = path.steps[step][bit];
+                    let (condition, satisfied) = match c {
+                        BitCondition::Zero => (c.to_char().to_string(), b1 == 0 && b2 == 0),
+                        BitCondition::One => (c.to_char().to_string(), b1 == 1 && b2 == 1),
+                        BitCondition::Up => (c.to_char().to_string(), b1 == 0 && b2 == 1),
+                        BitCondition::Down => (c.to_char().to_string(), b1 == 1 && b2 == 0),
+                        BitCondition::Differ => (c.to_char().to_string(), b1 != b2),
+                        BitCondition::Prev => relation(step as isize - 1, true),
+                        BitCondition::Prev2 => relation(step as isize - 2, true),
+                        BitCondition::NotPrev => relation(step as isize - 1, false),
+                        BitCondition::NotPrev2 => relation(step as isize - 2, false),
+                        BitCondition::Free => unreachable!(),
+                    };
+                    ConditionCheck {
+                        bit,
+                        condition,
+                        satisfied,
+                    }
+                })
+                .collect();
+
+            StepTrace {
+                step,
+                name: step_name(step as isize),
+                v1,
+                v2,
+                xor: v1 ^ v2,
+                diff: v2.wrapping_sub(v1),
+                conditions,
+            }
+        })
+        .collect();
+
+    Trace {
+        init: init.s,
+        m1: m1.to_vec(),
+        m2: m2.to_vec(),
+        steps,
+    }
+}
+
+impl Trace {
+    /// Number of violated sufficient conditions
+    pub fn violations(&self) -> usize {
+        self.steps
+            .iter()
+            .flat_map(|s| &s.conditions)
+            .filter(|c| !c.satisfied)
+            .count()
+    }
+
+    /// Render the trace as a human-readable table
+    pub fn table(&self) -> String {
+        let mut out = format!(
+            "{:<4} {:<4} {:<8} {:<8} {:<8} {:<8} conditions\n",
+            "step", "name", "v1", "v2", "xor", "diff"
+        );
+        for s in &self.steps {
+            let conditions = s.conditions.iter().format_with(" ", |c, f| {
+                f(&format_args!(
+                    "{}:{}{}",
+                    c.bit,
+                    c.condition,
+                    if c.satisfied { "" } else { "(!)" }
+                ))
+            });
+            let line = format!(
+                "{:<4} {:<4} {:08x} {:08x} {:08x} {:08x} {}",
+                s.step, s.name, s.v1, s.v2, s.xor, s.diff, conditions
+            );
+            out += line.trim_end();
+            out += "\n";
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use crate::collision::CollisionFinder;
+    use crate::common::*;
+    use crate::path::DifferentialPath;
+    use crate::state::MD4State;
+    use crate::trace::*;
+
+    #[test]
+    fn step_values_match_compression() {
+        let init = MD4State::new();
+        let mut data = U32Block::default();
+        for w in &mut data {
+            *w = rand::random();
+        }
+
+        let values = init.step_values(&data);
+        let out = init.process_u32array(&data);
+        assert_eq!(values[44].wrapping_add(init.s[0]), out.s[0]);
+        assert_eq!(values[47].wrapping_add(init.s[1]), out.s[1]);
+        assert_eq!(values[46].wrapping_add(init.s[2]), out.s[2]);
+        assert_eq!(values[45].wrapping_add(init.s[3]), out.s[3]);
+    }
+
+    #[test]
+    fn trace_collision() {
+        let init = MD4State::new();
+        let mut finder = CollisionFinder::from(init);
+        let (b1, _) = loop {
+            if let Some(pair) = finder.find_once() {
+                break pair;
+            }
+        };
+
+        let t = trace(&init, &b1, &DifferentialPath::wang());
+        assert_eq!(t.violations(), 0);
+        assert_eq!(t.steps[0].conditions[0].condition, "=b0");
+        assert_eq!(t.steps[1].conditions[0].condition, "u");
+        assert_eq!(t.steps[16].conditions[0].condition, "=c4");
+        assert_eq!(t.steps[17].conditions[0].condition, "=a5");
+
+        // Both messages end in the same state
+        for i in 44..48 {
+            assert_eq!(t.steps[i].diff, 0);
+        }
+    }
+
+    #[test]
+    fn trace_extended_lines() {
+        let init = ExtendedMD4State::new();
+        let block = U8Block::default();
+        let path = DifferentialPath::wang();
+
+        // The left line is plain MD4
+        let [left, right] = trace_extended(&init, &block, &path);
+        assert_eq!(
+            left.steps[47].v1,
+            trace(&init.left, &block, &path).steps[47].v1
+        );
+        assert_eq!(right.init, init.right.s);
+        // Round 1 has the same constants in both lines, rounds 2 and 3 differ
+        assert_ne!(left.steps[16].v1, right.steps[16].v1);
+        assert_eq!(
+            right.steps[15].v1,
+            trace(&init.right, &block, &path).steps[15].v1
+        );
+    }
+}
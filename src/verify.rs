@@ -0,0 +1,156 @@
+use crate::collision::WANG_DIFFERENTIAL;
+use crate::common::*;
+use crate::state::MD4State;
+use byteorder::{ByteOrder, LE};
+use md4::{Digest, Md4};
+
+/// Analysis of a claimed MD4 collision pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verification {
+    /// Index of the first 64-byte block where the messages differ
+    pub first_block: usize,
+    /// Index of the last 64-byte block where the messages differ
+    pub last_block: usize,
+    /// Chaining state shared by both messages before the first differing block
+    pub state_before: MD4State,
+    /// Chaining states after the differing region, if it ends on a full block
+    pub states_after: Option<(MD4State, MD4State)>,
+    /// Message word differences (M2 - M1) of the first differing block
+    pub deltas: U32Block,
+    /// Whether the differing region is a single block with the Wang differential
+    pub wang_differential: bool,
+    /// Whether both messages have the same MD4 digest
+    pub digests_equal: bool,
+}
+
+impl Verification {
+    /// Byte offset of the first differing block
+    pub fn offset(&self) -> u64 {
+        self.first_block as u64 * 64
+    }
+}
+
+fn read_words(block: &[u8]) -> U32Block {
+    // A trailing partial block is padded with zeros
+    let mut bytes = U8Block::default();
+    bytes[..block.len()].copy_from_slice(block);
+
+    let mut words = U32Block::default();
+    LE::read_u32_into(&bytes, &mut words);
+    words
+}
+
+/// Check whether message word differences are exactly the Wang differential,
+/// in either direction
+pub fn is_wang_differential(deltas: &U32Block) -> bool {
+    let matches = |sign: fn(u32) -> u32| {
+        (0..16).all(|i| {
+            let expected = WANG_DIFFERENTIAL
+                .iter()
+                .find(|(j, _)| *j == i)
+                .map_or(0, |(_, delta)| sign(*delta));
+            deltas[i] == expected
+        })
+    };
+
+    matches(|d| d) || matches(|d| d.wrapping_neg())
+}
+
+/// Locate and analyse the differing region of two messages
+pub fn verify(m1: &[u8], m2: &[u8]) -> Result<Verification, &'static str> {
+    if m1.len() != m2.len() {
+        return Err("files have different lengths");
+    }
+
+    let differs = |i: &usize| m1[*i] != m2[*i];
+    let first = match (0..m1.len()).find(differs) {
+        Some(first) => first,
+        None => return Err("files are identical"),
+    };
+    let last = (0..m1.len()).rev().find(differs).unwrap_or(first);
+
+    let first_block = first / 64;
+    let last_block = last / 64;
+
+    // Shared state before the differing region
+    let mut state_before = MD4State::new();
+    state_before.apply_blocks(&m1[..first_block * 64]);
+
+    // States after the differing region
+    let end = (last_block + 1) * 64;
+    let states_after = if end <= m1.len() {
+        let mut s1 = state_before;
+        let mut s2 = state_before;
+        s1.apply_blocks(&m1[first_block * 64..end]);
+        s2.apply_blocks(&m2[first_block * 64..end]);
+        Some((s1, s2))
+    } else {
+        None
+    };
+
+    // Message word differences of the first differing block
+    let block_end = m1.len().min((first_block + 1) * 64);
+    let w1 = read_words(&m1[first_block * 64..block_end]);
+    let w2 = read_words(&m2[first_block * 64..block_end]);
+    let mut deltas = U32Block::default();
+    for i in 0..16 {
+        deltas[i] = w2[i].wrapping_sub(w1[i]);
+    }
+
+    Ok(Verification {
+        first_block,
+        last_block,
+        state_before,
+        states_after,
+        wang_differential: first_block == last_block && is_wang_differential(&deltas),
+        deltas,
+        digests_equal: Md4::digest(m1) == Md4::digest(m2),
+    })
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use crate::builder::Builder;
+    use crate::verify::*;
+
+    #[test]
+    fn verify_generated_collision() {
+        let prefix = vec![rand::random(); 200];
+        let mut builder = Builder::new();
+        builder.input(&prefix);
+        let (padding, b1, b2) = builder.build().unwrap();
+
+        let m1 = [&prefix[..], &padding, &b1, b"common tail"].concat();
+        let m2 = [&prefix[..], &padding, &b2, b"common tail"].concat();
+
+        let v = verify(&m1, &m2).unwrap();
+        assert_eq!(v.first_block, 4);
+        assert_eq!(v.last_block, 4);
+        assert_eq!(v.offset(), 256);
+        let (s1, s2) = v.states_after.unwrap();
+        assert_eq!(s1, s2);
+        assert!(v.wang_differential);
+        assert!(v.digests_equal);
+
+        // Swapping the pair negates the differences
+        assert!(verify(&m2, &m1).unwrap().wang_differential);
+    }
+
+    #[test]
+    fn verify_non_collision() {
+        let m1 = vec![0u8; 130];
+        let mut m2 = m1.clone();
+        m2[70] = 1;
+        m2[129] = 1;
+
+        let v = verify(&m1, &m2).unwrap();
+        assert_eq!(v.first_block, 1);
+        assert_eq!(v.last_block, 2);
+        assert_eq!(v.states_after, None);
+        assert!(!v.wang_differential);
+        assert!(!v.digests_equal);
+
+        assert!(verify(&m1, &m1).is_err());
+        assert!(verify(&m1, &m1[1..]).is_err());
+    }
+}